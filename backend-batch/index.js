@@ -93,15 +93,18 @@ async function main() {
           for (const outcome of receipts_outcome) {
               if (outcome.outcome.logs.length > 0) {
                   for (const log of outcome.outcome.logs) {
-                      if (log.startsWith('<swapLog>')) {
-                        let logVal = log.replace('<swapLog> ', '');
-                        logStream.write(`Swap logs: ${logVal}\n`);
-                        const json = JSON.parse(logVal);
+                      if (!log.startsWith('EVENT_JSON:')) {
+                        continue;
+                      }
+                      const event = JSON.parse(log.replace('EVENT_JSON:', ''));
+                      if (event.standard === 'near-dca' && event.event === 'position_swapped') {
+                        logStream.write(`Swap logs: ${JSON.stringify(event.data)}\n`);
+                        const json = event.data;
                         logStream.write(`Swap logs: ${outcome.outcome.logs}\n`);
-                        registerConversion(DB_FILE, outcome.id, json.user, json.source_amount, json.target_amount, json.source, json.target);
+                        registerConversion(DB_FILE, outcome.id, json.account_id, json.source_amount, json.target_amount, json.source, json.target);
 
                         // check if a user subscribed to telegram notification for the user address
-                        const registeredAddresses = await getTelegramUsers(DB_FILE, json.user);
+                        const registeredAddresses = await getTelegramUsers(DB_FILE, json.account_id);
 
                         logStream.write(`Registered addresses: ${JSON.stringify(registeredAddresses)}\n`);
 
@@ -109,8 +112,8 @@ async function main() {
                         if(registeredAddresses.length > 0) {
                           const bot = new Telegraf(TELEGRAM_BOT_TOKEN)
                           registeredAddresses.forEach(registeredAddress => {
-                              logStream.write(`User ${registeredAddress.telegram_id} subscribed to telegram notification for ${json.user}... sending message\n`);
-                              bot.telegram.sendMessage(registeredAddress.telegram_id, `🔄💸 Conversion Alert! 💸🔄\n\n👤 User: ${json.user}\n💰 ${json.source_amount} ${json.source} ➡️ ${json.target_amount} ${json.target}\n🚀`);
+                              logStream.write(`User ${registeredAddress.telegram_id} subscribed to telegram notification for ${json.account_id}... sending message\n`);
+                              bot.telegram.sendMessage(registeredAddress.telegram_id, `🔄💸 Conversion Alert! 💸🔄\n\n👤 User: ${json.account_id}\n💰 ${json.source_amount} ${json.source} ➡️ ${json.target_amount} ${json.target}\n🚀`);
                           });
                         }
 
@@ -150,15 +153,18 @@ async function main() {
         for (const outcome of receipts_outcome) {
             if (outcome.outcome.logs.length > 0) {
                 for (const log of outcome.outcome.logs) {
-                    if (log.startsWith('<swapLog>')) {
-                      let logVal = log.replace('<swapLog> ', '');
-                      logStream.write(`Swap logs: ${logVal}\n`);
-                      const json = JSON.parse(logVal);
+                    if (!log.startsWith('EVENT_JSON:')) {
+                      continue;
+                    }
+                    const event = JSON.parse(log.replace('EVENT_JSON:', ''));
+                    if (event.standard === 'near-dca' && event.event === 'position_swapped') {
+                      logStream.write(`Swap logs: ${JSON.stringify(event.data)}\n`);
+                      const json = event.data;
                       logStream.write(`Swap logs: ${outcome.outcome.logs}\n`);
-                      registerConversion(DB_FILE, outcome.id, json.user, json.source_amount, json.target_amount, json.source, json.target);
+                      registerConversion(DB_FILE, outcome.id, json.account_id, json.source_amount, json.target_amount, json.source, json.target);
 
                       // check if a user subscribed to telegram notification for the user address
-                      const registeredAddresses = await getTelegramUsers(DB_FILE, json.user);
+                      const registeredAddresses = await getTelegramUsers(DB_FILE, json.account_id);
 
                       logStream.write(`Registered addresses: ${JSON.stringify(registeredAddresses)}\n`);
 
@@ -166,8 +172,8 @@ async function main() {
                       if(registeredAddresses.length > 0) {
                         const bot = new Telegraf(TELEGRAM_BOT_TOKEN)
                         registeredAddresses.forEach(registeredAddress => {
-                            logStream.write(`User ${registeredAddress.telegram_id} subscribed to telegram notification for ${json.user}... sending message\n`);
-                            bot.telegram.sendMessage(registeredAddress.telegram_id, `🔄💸 Conversion Alert! 💸🔄\n\n👤 User: ${json.user}\n💰 ${json.source_amount} ${json.source} ➡️ ${json.target_amount} ${json.target}\n🚀`);
+                            logStream.write(`User ${registeredAddress.telegram_id} subscribed to telegram notification for ${json.account_id}... sending message\n`);
+                            bot.telegram.sendMessage(registeredAddress.telegram_id, `🔄💸 Conversion Alert! 💸🔄\n\n👤 User: ${json.account_id}\n💰 ${json.source_amount} ${json.source} ➡️ ${json.target_amount} ${json.target}\n🚀`);
                         });
                       }
 
@@ -450,10 +456,16 @@ bot.command('check', async (ctx) => {
     logStreamBot.write(`${new Date().toISOString()} -- Valid address: ${isValid}\n`)
   }
 
-  let status = await getNearAccountBalance(config, CONTRACT_ID, address)
-  if (status) {  
-    ctx.reply(`Account status:\nAmount per swap: ${status.amount_per_swap}\nSwap interval: ${status.swap_interval}\nLast swap timestamp: ${status.last_swap_timestamp}\nTarget amount: ${status.total_swapped}\nPaused: ${status.pause}`)
-    logStreamBot.write(`${new Date().toISOString()} -- Status: ${status}\n`)
+  let positions = await getNearAccountBalance(config, CONTRACT_ID, address)
+  if (Array.isArray(positions) && positions.length === 0) {
+    ctx.reply(`${address} has no DCA position`)
+    logStreamBot.write(`${new Date().toISOString()} -- No position: ${address}\n`)
+  } else if (positions) {
+    // one block per position of the account
+    positions.forEach(position => {
+      ctx.reply(`Position ${position.id}:\nAmount per swap: ${position.amount_per_swap}\nSwap interval: ${position.swap_interval}\nLast swap timestamp: ${position.last_swap_timestamp}\nTarget amount: ${position.total_swapped}\nPaused: ${position.pause}`)
+    })
+    logStreamBot.write(`${new Date().toISOString()} -- Status: ${JSON.stringify(positions)}\n`)
   } else {
     ctx.reply('Error getting status')
     logStreamBot.write(`${new Date().toISOString()} -- Error getting status: ${address}\n`)
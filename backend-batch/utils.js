@@ -19,9 +19,9 @@ async function getNearAccountBalance(nearConfig, contractId, accountId) {
     const account = await near.account(accountId);
     const responseView = await account.viewFunction({
         contractId: contractId,
-        methodName: 'get_user',
+        methodName: 'get_positions',
         args: {
-            user: accountId
+            account_id: accountId
         },
     });
 
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+
+use near_sdk::json_types::U128;
+use near_sdk::{env, log, near, serde_json, AccountId, Gas};
+
+use crate::events::DcaEvent;
+use crate::ext::{ext_wrap, Venue};
+use crate::math::mul_div;
+use crate::pair::{Hop, Pair, PairId};
+use crate::position::PositionId;
+use crate::receiver::TransferMessage;
+use crate::stats::Fill;
+use crate::{Contract, GAS_FOR_BATCH_POSITION, GAS_FOR_QUOTE_CALLBACK, GAS_FOR_QUOTE_HOP, GAS_FOR_REFUND_CALLBACK, GAS_FOR_SETTLE_RESERVE, GAS_FOR_SWAP, GAS_FOR_SWAP_TRANSFER_CALLBACK, GAS_FOR_TRANSFER_CALLBACK, GAS_FOR_PRE_WITHDRAW_CALLBACK, MIN_GAS_PER_POSITION, YOCTO_DEPOSIT};
+
+pub type BatchId = u64;
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchStatus {
+    // funds are on their way to the pool or being swapped
+    Pending,
+    // the swap went through, the output is being withdrawn from the pool or sent back
+    // by the venue
+    Swapped,
+    // the output reached the contract and is being credited to the positions, over
+    // several receipts when they do not fit in one
+    Settling,
+    // the output reached the contract and was credited to the positions
+    Settled,
+    // the batch did not go through, positions were not charged
+    Failed,
+}
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone)]
+pub struct Batch {
+    pub id: BatchId,
+    pub pair_id: PairId,
+    pub reverse: bool,
+    // venue and pools the batch is swapped through, from the pair when it was created
+    pub venue: Venue,
+    pub route: Vec<Hop>,
+    // account that started the batch, paid the keeper bounty once it settles
+    pub keeper: AccountId,
+    pub positions: Vec<PositionId>,
+    // owner of each position, in the same order
+    pub accounts: Vec<AccountId>,
+    // sum of the amount_per_swap of the positions in the batch
+    pub amount: U128,
+    // amount sent to the pool once fees are taken
+    pub amount_in: U128,
+    // protocol fee kept from amount, accrued once the batch settles
+    pub fee: U128,
+    // least output accepted from the pool, from the quote taken before the swap
+    pub min_amount_out: U128,
+    // output quoted by the pool for amount_in before the swap
+    pub quoted_amount_out: U128,
+    // output of the swap, set once the batch is swapped
+    pub amount_out: U128,
+    // gas carried from the quote to the settlement for crediting the positions and
+    // finishing the batch
+    pub settlement_gas: Gas,
+    // output plus the dust carried over, split between the positions on settlement
+    pub distributable: U128,
+    // part of distributable credited so far, and to how many positions
+    pub distributed: U128,
+    pub settled_positions: u32,
+    pub status: BatchStatus,
+    // taken from the bond of the keeper for this batch, once it failed
+    pub slashed: U128,
+    // when the batch was started, swapped, and settled or failed
+    pub timestamp: u64,
+    pub swapped_timestamp: Option<u64>,
+    pub finished_timestamp: Option<u64>,
+}
+
+impl Contract {
+    pub(crate) fn internal_create_batch(&mut self, pair_id: PairId, reverse: bool, keeper: AccountId, positions: Vec<PositionId>, amount: U128, amount_in: U128) -> BatchId {
+        let batch_id = self.next_batch_id;
+        self.next_batch_id += 1;
+        let settlement_gas = GAS_FOR_SETTLE_RESERVE.saturating_add(self.gas_per_position.saturating_mul(positions.len() as u64));
+        let accounts = self.position_accounts(&positions);
+
+        self.batches.insert(batch_id, Batch {
+            id: batch_id,
+            pair_id,
+            reverse,
+            venue: self.internal_get_pair(pair_id).venue,
+            route: self.internal_get_pair(pair_id).route(reverse),
+            keeper,
+            positions,
+            accounts,
+            amount,
+            amount_in,
+            fee: U128(amount.0 - amount_in.0),
+            min_amount_out: U128(0),
+            quoted_amount_out: U128(0),
+            amount_out: U128(0),
+            settlement_gas,
+            distributable: U128(0),
+            distributed: U128(0),
+            settled_positions: 0,
+            status: BatchStatus::Pending,
+            slashed: U128(0),
+            timestamp: env::block_timestamp(),
+            swapped_timestamp: None,
+            finished_timestamp: None,
+        });
+
+        batch_id
+    }
+
+    /// Owners of the positions, in the same order.
+    pub(crate) fn position_accounts(&self, positions: &[PositionId]) -> Vec<AccountId> {
+        positions.iter().map(|position_id| self.internal_get_position(*position_id).wallet).collect()
+    }
+
+    pub(crate) fn internal_get_batch(&self, batch_id: BatchId) -> Batch {
+        self.batches.get(&batch_id).expect("Batch does not exist").clone()
+    }
+
+    /// Reserves the positions for the batch, they are taken out of the due index.
+    pub(crate) fn internal_lock_positions(&mut self, positions: &[PositionId], batch_id: BatchId) {
+        for &position_id in positions {
+            let mut position = self.internal_get_position(position_id);
+            position.in_flight = Some(batch_id);
+            self.internal_save_position(position);
+        }
+    }
+
+    /// Releases positions that are no longer part of a batch, they are due again.
+    pub(crate) fn internal_release_positions(&mut self, positions: &[PositionId]) {
+        for &position_id in positions {
+            let mut position = self.internal_get_position(position_id);
+            position.in_flight = None;
+            self.internal_save_position(position);
+        }
+    }
+
+    /// Whether no batch is moving the token through the contract's deposit on the venue.
+    /// Venues swapping on transfer keep no deposit, they are always free.
+    pub(crate) fn is_pool_free(&self, venue: &Venue, token_id: &AccountId) -> bool {
+        !venue.keeps_deposits() || !self.pool_locks.contains_key(&(venue.contract_id().clone(), token_id.clone()))
+    }
+
+    /// Whether a batch of the pair can use the deposits of both its tokens on its venue.
+    pub(crate) fn is_pair_pool_free(&self, pair: &Pair) -> bool {
+        self.is_pool_free(&pair.venue, &pair.token_in) && self.is_pool_free(&pair.venue, &pair.token_out)
+    }
+
+    /// Reserves the deposit of the token on the venue of the batch, so the deposit only
+    /// moves with the batch until it is unlocked.
+    pub(crate) fn internal_lock_pool(&mut self, batch_id: BatchId, token_id: AccountId) {
+        let venue = self.internal_get_batch(batch_id).venue;
+        if venue.keeps_deposits() {
+            self.pool_locks.insert((venue.contract_id().clone(), token_id), batch_id);
+        }
+    }
+
+    /// Frees the deposits of both tokens of the batch held by it on its venue.
+    pub(crate) fn internal_unlock_pool(&mut self, batch_id: BatchId) {
+        let batch = self.internal_get_batch(batch_id);
+        let (token_in, token_out) = self.batch_tokens(&batch);
+        for token_id in [token_in, token_out] {
+            let key = (batch.venue.contract_id().clone(), token_id);
+            if self.pool_locks.get(&key) == Some(&batch_id) {
+                self.pool_locks.remove(&key);
+            }
+        }
+    }
+
+    /// Most positions a batch of the pair can hold with the given gas attached to swap,
+    /// which pays for the quotes of the route, for locking and crediting each position
+    /// and for finishing the settlement, up to the batch threshold.
+    pub(crate) fn max_batch_positions(&self, pair: &Pair, gas: Gas) -> usize {
+        let spare_gas = gas.saturating_sub(self.batch_gas(pair, 0));
+        let position_gas = self.batch_gas(pair, 1).saturating_sub(self.batch_gas(pair, 0));
+        (spare_gas.as_gas() / position_gas.as_gas()).min(self.batch_swap_threshold.into()) as usize
+    }
+
+    /// Gas of the whole pipeline of a batch of the pair with the given number of positions.
+    pub(crate) fn batch_gas(&self, pair: &Pair, positions: usize) -> Gas {
+        let hops_left = (pair.route.len() - 1) as u64;
+        GAS_FOR_SWAP
+            .saturating_add(GAS_FOR_QUOTE_CALLBACK)
+            .saturating_add(GAS_FOR_QUOTE_HOP.saturating_mul(hops_left))
+            .saturating_add(GAS_FOR_SETTLE_RESERVE)
+            .saturating_add(self.gas_per_position.saturating_add(GAS_FOR_BATCH_POSITION).saturating_mul(positions as u64))
+    }
+
+    /// Tokens sent to and received from the pool by the batch.
+    pub(crate) fn batch_tokens(&self, batch: &Batch) -> (AccountId, AccountId) {
+        self.internal_get_pair(batch.pair_id).tokens(batch.reverse)
+    }
+
+    /// Sum of the swap amounts of the given positions, before and after fees. The fee
+    /// is taken on each swap, so the batch fee is the sum of the fees of its positions.
+    pub(crate) fn internal_batch_amounts(&self, positions: &[PositionId]) -> (U128, U128) {
+        let (amount, amount_in) = positions.iter()
+            .map(|position_id| self.positions.get(position_id).unwrap().amount_per_swap.0)
+            .fold((0u128, 0u128), |(amount, amount_in), amount_per_swap| {
+                (amount + amount_per_swap, amount_in + self.amount_after_fees(amount_per_swap))
+            });
+        (U128(amount), U128(amount_in))
+    }
+
+    /// Quotes one hop of the route of the batch, for the batch and for the reference
+    /// amount, and looks at its pool. Each hop is quoted with the outputs quoted for
+    /// the previous one.
+    pub(crate) fn internal_quote_hop(&self, batch_id: BatchId, hop: u8, amount: U128, hop_reference_amount: U128, reference_amount: U128) {
+        let batch = self.internal_get_batch(batch_id);
+        let route_hop = &batch.route[usize::from(hop)];
+        // the callback quotes the hops that are left before going on with the batch
+        let hops_left = (batch.route.len() - 1 - usize::from(hop)) as u64;
+
+        batch.venue.quote(route_hop, amount)
+            .and(batch.venue.quote(route_hop, hop_reference_amount))
+            .and(batch.venue.get_pool(route_hop))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_QUOTE_CALLBACK.saturating_add(GAS_FOR_QUOTE_HOP.saturating_mul(hops_left)).saturating_add(batch.settlement_gas))
+                    .pool_quote_callback(batch_id, hop, reference_amount),
+            );
+    }
+
+    /// Sends the input of the batch to the venue, to be swapped there or deposited for
+    /// a separate swap.
+    pub(crate) fn internal_pool_deposit(&self, batch_id: BatchId) {
+        let batch = self.internal_get_batch(batch_id);
+        let (token_in, _) = self.batch_tokens(&batch);
+        let echo = serde_json::to_string(&TransferMessage::BatchOutput { batch_id }).unwrap();
+        let message = batch.venue.deposit_message(&batch.route, batch.amount_in.0, batch.min_amount_out.0, echo);
+        // venues swapping on transfer leave the callback at most the settlement
+        let callback_gas = if batch.venue.keeps_deposits() { GAS_FOR_TRANSFER_CALLBACK } else { GAS_FOR_SWAP_TRANSFER_CALLBACK };
+
+        ext_wrap::ext(token_in)
+            .with_static_gas(batch.venue.deposit_gas())
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .ft_transfer_call(batch.venue.contract_id().clone(), batch.amount_in, Some(message))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(callback_gas.saturating_add(batch.settlement_gas))
+                    .pool_transfer_callback(batch_id),
+            );
+    }
+
+    /// Withdraws the output of a swapped batch from the pool. Positions are credited
+    /// once the withdraw is confirmed.
+    pub(crate) fn internal_pool_withdraw(&self, batch_id: BatchId) {
+        let batch = self.internal_get_batch(batch_id);
+        let (_, token_out) = self.batch_tokens(&batch);
+
+        // the deposit of the token is read before and after the withdraw
+        batch.venue.deposit_of(env::current_account_id(), token_out)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_PRE_WITHDRAW_CALLBACK.saturating_add(batch.settlement_gas))
+                    .pool_pre_withdraw_callback(batch_id),
+            );
+    }
+
+    /// Brings the input of a batch that could not be swapped back from the pool. The
+    /// deposit of the token stays locked for the batch until the refund resolves.
+    pub(crate) fn internal_pool_refund(&mut self, batch_id: BatchId, token_id: AccountId, amount: U128) {
+        self.internal_lock_pool(batch_id, token_id.clone());
+        self.internal_get_batch(batch_id).venue.withdraw(token_id.clone(), amount)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_REFUND_CALLBACK)
+                    .pool_refund_callback(batch_id, token_id, amount),
+            );
+    }
+
+    /// Takes the output of a batch sent back by its venue with ft_transfer_call. The
+    /// batch is settled by whichever of the output and the callback of the transfer of
+    /// the input comes last, preferably the callback, which carries the settlement gas.
+    pub(crate) fn internal_receive_batch_output(&mut self, token_id: &AccountId, sender_id: &AccountId, batch_id: BatchId, amount: U128) -> Result<(), &'static str> {
+        let batch = self.batches.get(&batch_id).ok_or("Batch does not exist")?;
+        if batch.venue.keeps_deposits() || batch.venue.contract_id() != sender_id {
+            return Err("Output must be sent by the venue of the batch");
+        }
+        if &self.batch_tokens(batch).1 != token_id {
+            return Err("Output is not the token bought by the batch");
+        }
+        // the callback of the transfer of the input may come before or after the output
+        if batch.status != BatchStatus::Pending && batch.status != BatchStatus::Swapped {
+            // an output coming after the batch was settled by the owner would likely be
+            // lost if refunded to the venue, it is carried over to the next batch instead
+            let dust = self.dust.get(token_id).map_or(0, |dust| dust.0);
+            self.dust.insert(token_id.clone(), U128(dust.checked_add(amount.0).expect("Overflow")));
+            log!("Output of batch {} came after it was finished, it is added to the dust", batch_id);
+            return Ok(());
+        }
+
+        if batch.amount_out.0 > 0 {
+            return Err("Output of the batch was already received");
+        }
+
+        let batch = self.batches.get_mut(&batch_id).unwrap();
+        let transfer_resolved = batch.status == BatchStatus::Swapped;
+        batch.status = BatchStatus::Swapped;
+        batch.swapped_timestamp.get_or_insert(env::block_timestamp());
+        batch.amount_out = amount;
+        if transfer_resolved {
+            // settled with the gas the venue forwarded, settle_batch credits the rest
+            self.internal_settle_batch(batch_id);
+        }
+        Ok(())
+    }
+
+    /// Credits the output of a swapped batch, which reached the contract, to its
+    /// positions. Positions are credited while the gas lasts, the rest of the batch is
+    /// handed over to another receipt with a budget of its own, or to `settle_batch`
+    /// when too little gas is left. Returns the new bought balance of the positions
+    /// credited here.
+    pub(crate) fn internal_settle_batch(&mut self, batch_id: BatchId) -> HashMap<PositionId, u128> {
+        let mut batch = self.internal_get_batch(batch_id);
+        let (token_in, token_out) = self.batch_tokens(&batch);
+
+        if batch.status != BatchStatus::Settling {
+            // output of the batch plus the rounding left over by the previous ones,
+            // taken now so batches settling meanwhile do not share it
+            let carried_dust = self.dust.remove(&token_out).map_or(0, |dust| dust.0);
+            batch.distributable = U128(batch.amount_out.0.checked_add(carried_dust).expect("Overflow"));
+            batch.status = BatchStatus::Settling;
+        }
+
+        // initialize the return value
+        let mut return_value: HashMap<PositionId, u128> = HashMap::new();
+
+        // cost of crediting a position, measured on the positions credited here
+        let mut position_gas = self.gas_per_position;
+        let step_start_gas = env::used_gas();
+        let step_start = batch.settled_positions as usize;
+
+        // update last_swap_timestamp, total_swapped and amount for positions in the batch
+        for (index, &position_id) in batch.positions.iter().enumerate().skip(step_start) {
+            let remaining_gas = env::prepaid_gas().saturating_sub(env::used_gas());
+            // a step that cannot credit every position left also keeps the least a
+            // next step needs, so handing over always makes progress
+            let positions_left = (batch.positions.len() - index) as u64;
+            let mut kept_gas = GAS_FOR_SETTLE_RESERVE;
+            if remaining_gas < position_gas.saturating_mul(positions_left).saturating_add(GAS_FOR_SETTLE_RESERVE) {
+                kept_gas = kept_gas.saturating_add(GAS_FOR_SETTLE_RESERVE).saturating_add(position_gas);
+            }
+            if remaining_gas < position_gas.saturating_add(kept_gas) {
+                break;
+            }
+            let position_start_gas = env::used_gas();
+
+            let mut position = self.internal_get_position(position_id);
+            position.in_flight = None;
+            position.last_swap_timestamp = env::block_timestamp();
+            // exact share of the output, proportional to the amount swapped
+            let target_amount = mul_div(position.amount_per_swap.0, batch.distributable.0, batch.amount.0);
+            batch.distributed.0 += target_amount;
+            // the fee was already kept from the input of the batch
+            let fee_amount = self.fee_of(position.amount_per_swap.0);
+            position.total_swapped = U128(position.total_swapped.0.checked_add(target_amount).expect("Overflow"));
+            let new_amount = position.amount.0.checked_sub(position.amount_per_swap.0).expect("Insufficient funds");
+            position.amount = U128(new_amount);
+            // share of the quote, to compare with what was actually received
+            let quoted_amount = mul_div(position.amount_per_swap.0, batch.quoted_amount_out.0, batch.amount.0);
+            DcaEvent::PositionSwapped {
+                batch_id,
+                pair_id: batch.pair_id,
+                position_id,
+                account_id: &position.wallet,
+                source: &token_in,
+                source_amount: position.amount_per_swap,
+                target: &token_out,
+                target_amount: U128(target_amount),
+                quoted_target_amount: U128(quoted_amount),
+                fee_amount: U128(fee_amount),
+            }.emit();
+            self.internal_record_fill(&position.wallet, (&token_in, &token_out), Fill {
+                batch_id,
+                position_id,
+                pair_id: batch.pair_id,
+                reverse: batch.reverse,
+                amount_in: position.amount_per_swap,
+                amount_out: U128(target_amount),
+                fee: U128(fee_amount),
+                timestamp: env::block_timestamp(),
+            });
+            // add to return value
+            return_value.insert(position_id, position.total_swapped.0);
+            self.internal_save_position(position);
+
+            batch.settled_positions += 1;
+            position_gas = position_gas.max(env::used_gas().saturating_sub(position_start_gas));
+        }
+
+        // the average cost of the positions credited here sizes the next batches
+        let credited = batch.settled_positions as u64 - step_start as u64;
+        if let Some(measured) = env::used_gas().saturating_sub(step_start_gas).as_gas().checked_div(credited) {
+            let smoothed = (self.gas_per_position.as_gas() * 3 + measured) / 4;
+            self.gas_per_position = Gas::from_gas(smoothed).max(MIN_GAS_PER_POSITION);
+        }
+
+        if (batch.settled_positions as usize) < batch.positions.len() {
+            // the next step is given what the positions left need, as far as the gas
+            // kept here allows. A step that credited nothing does not hand over, the
+            // next one would have even less gas.
+            let positions_left = (batch.positions.len() - batch.settled_positions as usize) as u64;
+            let needed_gas = GAS_FOR_SETTLE_RESERVE.saturating_add(position_gas.saturating_mul(positions_left));
+            let spare_gas = env::prepaid_gas().saturating_sub(env::used_gas()).saturating_sub(GAS_FOR_SETTLE_RESERVE);
+            let step_gas = needed_gas.min(spare_gas);
+            self.batches.insert(batch_id, batch);
+            if credited > 0 && step_gas >= GAS_FOR_SETTLE_RESERVE.saturating_add(position_gas) {
+                Self::ext(env::current_account_id())
+                    .with_static_gas(step_gas)
+                    .settle_batch(batch_id);
+            } else {
+                log!("Batch {} is partly settled, settle_batch credits the rest", batch_id);
+            }
+            return return_value;
+        }
+
+        // whatever the rounding left is carried over to the next batch
+        let dust = self.dust.get(&token_out).map_or(0, |dust| dust.0);
+        self.dust.insert(token_out.clone(), U128(dust + batch.distributable.0 - batch.distributed.0));
+        // positions are charged now, so is the fee, shared with the keeper
+        let bounty = self.keeper_bounty_of(batch.fee.0);
+        self.internal_reward_keeper(&batch.keeper, token_in.clone(), bounty);
+        self.internal_accrue_fee(token_in.clone(), batch.fee.0 - bounty);
+        self.internal_record_batch_outcome(&batch.keeper, batch_id, true);
+        self.internal_track_settled_batch(&batch, &token_in);
+        batch.status = BatchStatus::Settled;
+        batch.finished_timestamp = Some(env::block_timestamp());
+        DcaEvent::FeeAccrued { token_id: &token_in, amount: batch.fee, keeper: &batch.keeper, keeper_bounty: U128(bounty) }.emit();
+        DcaEvent::BatchSettled {
+            batch_id,
+            pair_id: batch.pair_id,
+            reverse: batch.reverse,
+            amount_in: batch.amount_in,
+            amount_out: batch.amount_out,
+            fee: batch.fee,
+        }.emit();
+        self.batches.insert(batch_id, batch);
+
+        return_value
+    }
+
+    /// Marks the batch as failed. Balances and `last_swap_timestamp` of its positions
+    /// are left untouched, so they stay due and are picked up by the next batch.
+    pub(crate) fn internal_fail_batch(&mut self, batch_id: BatchId, reason: &str) {
+        let batch = self.batches.get_mut(&batch_id).expect("Batch does not exist");
+        batch.status = BatchStatus::Failed;
+        batch.finished_timestamp = Some(env::block_timestamp());
+
+        DcaEvent::BatchFailed { batch_id, pair_id: batch.pair_id, reverse: batch.reverse, amount: batch.amount_in, reason }.emit();
+        let keeper = batch.keeper.clone();
+        let positions = batch.positions.clone();
+        self.metrics.batches_failed += 1;
+        self.internal_record_batch_outcome(&keeper, batch_id, false);
+        self.internal_release_positions(&positions);
+        self.internal_unlock_pool(batch_id);
+    }
+}
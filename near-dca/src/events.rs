@@ -0,0 +1,89 @@
+use near_sdk::json_types::U128;
+use near_sdk::{near, serde_json, AccountId};
+
+use crate::batch::BatchId;
+use crate::pair::PairId;
+use crate::position::PositionId;
+
+// Events of the contract, logged as NEP-297 events, e.g.
+// `EVENT_JSON:{"standard":"near-dca","version":"1.0.0","event":"batch_failed","data":{...}}`.
+// Amounts are strings, like everywhere else in the JSON interface.
+#[near(event_json(standard = "near-dca"))]
+pub enum DcaEvent<'a> {
+    #[event_version("1.0.0")]
+    PositionCreated {
+        position_id: PositionId,
+        account_id: &'a AccountId,
+        pair_id: PairId,
+        reverse: bool,
+        amount_per_swap: U128,
+        swap_interval: u64,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    PositionToppedUp { position_id: PositionId, account_id: &'a AccountId, token_id: &'a AccountId, amount: U128 },
+    #[event_version("1.0.0")]
+    PositionWithdrawn { position_id: PositionId, account_id: &'a AccountId, token_id: &'a AccountId, amount: U128 },
+    #[event_version("1.0.0")]
+    PositionPaused { position_id: PositionId, account_id: &'a AccountId },
+    #[event_version("1.0.0")]
+    PositionResumed { position_id: PositionId, account_id: &'a AccountId },
+    #[event_version("1.0.0")]
+    PositionClosed { position_id: PositionId, account_id: &'a AccountId },
+    #[event_version("1.0.0")]
+    BatchStarted {
+        batch_id: BatchId,
+        pair_id: PairId,
+        reverse: bool,
+        keeper: &'a AccountId,
+        positions: &'a [PositionId],
+        // input of the positions, before and after the protocol fee
+        amount: U128,
+        amount_in: U128,
+    },
+    // the swap of one position of a settled batch
+    #[event_version("1.0.0")]
+    PositionSwapped {
+        batch_id: BatchId,
+        pair_id: PairId,
+        position_id: PositionId,
+        account_id: &'a AccountId,
+        source: &'a AccountId,
+        source_amount: U128,
+        target: &'a AccountId,
+        target_amount: U128,
+        quoted_target_amount: U128,
+        fee_amount: U128,
+    },
+    #[event_version("1.0.0")]
+    BatchSettled { batch_id: BatchId, pair_id: PairId, reverse: bool, amount_in: U128, amount_out: U128, fee: U128 },
+    #[event_version("1.0.0")]
+    BatchFailed { batch_id: BatchId, pair_id: PairId, reverse: bool, amount: U128, reason: &'a str },
+    // protocol fee of a settled batch, with the part of it paid to the keeper
+    #[event_version("1.0.0")]
+    FeeAccrued { token_id: &'a AccountId, amount: U128, keeper: &'a AccountId, keeper_bounty: U128 },
+    // the output of a swapped batch is still in the pool, retry_batch_withdraw pulls it
+    #[event_version("1.0.0")]
+    BatchWithdrawFailed { batch_id: BatchId, token_id: &'a AccountId, amount: U128 },
+    // the input of a failed batch is still in the pool, recover_pool_balance pulls it
+    #[event_version("1.0.0")]
+    BatchRefundFailed { batch_id: BatchId, token_id: &'a AccountId, amount: U128 },
+    #[event_version("1.0.0")]
+    KeeperBondDeposited { keeper: &'a AccountId, amount: U128, bond: U128 },
+    #[event_version("1.0.0")]
+    KeeperUnregistered { keeper: &'a AccountId, unbonding_since: u64 },
+    #[event_version("1.0.0")]
+    KeeperBondWithdrawn { keeper: &'a AccountId, amount: U128 },
+    #[event_version("1.0.0")]
+    KeeperSlashed { keeper: &'a AccountId, batch_id: BatchId, amount: U128, reason: &'a str },
+    // a setting changed by the owner, with its new value
+    #[event_version("1.0.0")]
+    ConfigChanged { setting: &'a str, value: serde_json::Value },
+}
+
+impl DcaEvent<'_> {
+    /// Event of a changed setting, with its new value as JSON.
+    pub fn config_changed(setting: &str, value: impl near_sdk::serde::Serialize) {
+        DcaEvent::ConfigChanged { setting, value: serde_json::to_value(value).unwrap() }.emit();
+    }
+}
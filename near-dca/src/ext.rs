@@ -1,32 +1,262 @@
 use near_sdk::json_types::U128;
-use near_sdk::{ext_contract, near, AccountId, PromiseOrValue};
+use near_sdk::{ext_contract, near, serde_json, AccountId, Gas, Promise, PromiseOrValue};
 
+use crate::pair::Hop;
+use crate::{GAS_FOR_POOL_DEPOSIT, GAS_FOR_POOL_QUOTE, GAS_FOR_POOL_OUTPUT_TRANSFER, GAS_FOR_POOL_SWAP, GAS_FOR_POOL_WITHDRAW, YOCTO_DEPOSIT};
+
+// DEX the swaps of a pair are executed on
+#[near(serializers = [json, borsh])]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Venue {
+    // Ref v2 classic pools: the input is deposited, swapped against the deposit and
+    // the output is withdrawn
+    RefV2 { contract_id: AccountId },
+    // Jumbo, a fork of Ref classic pools with the same deposit, swap and withdraw flow
+    Jumbo { contract_id: AccountId },
+    // Ref DCL concentrated liquidity pools: the input is swapped as it is transferred
+    // and the output is sent back with ft_transfer_call
+    RefDcl { contract_id: AccountId },
+    // Ref v2 classic pools swapped by a single ft_transfer_call carrying the actions.
+    // Ref sends the output back with ft_transfer_call, which needs this contract to be
+    // allowed to use client_echo on Ref.
+    RefV2Instant { contract_id: AccountId },
+}
+
+impl Venue {
+    pub fn contract_id(&self) -> &AccountId {
+        match self {
+            Venue::RefV2 { contract_id } | Venue::Jumbo { contract_id } | Venue::RefDcl { contract_id } | Venue::RefV2Instant { contract_id } => contract_id,
+        }
+    }
+
+    /// Whether the venue keeps deposits in an internal balance of the contract, to be
+    /// swapped and withdrawn with separate calls.
+    pub fn keeps_deposits(&self) -> bool {
+        !matches!(self, Venue::RefDcl { .. } | Venue::RefV2Instant { .. })
+    }
+
+    /// Quotes the output of one hop for `amount`.
+    pub fn quote(&self, hop: &Hop, amount: U128) -> Promise {
+        match self {
+            Venue::RefV2 { contract_id } | Venue::Jumbo { contract_id } | Venue::RefV2Instant { contract_id } => ref_contract::ext(contract_id.clone())
+                .with_static_gas(GAS_FOR_POOL_QUOTE)
+                .get_return(hop.pool_id, hop.token_in.clone(), amount, hop.token_out.clone()),
+            Venue::RefDcl { contract_id } => ref_dcl::ext(contract_id.clone())
+                .with_static_gas(GAS_FOR_POOL_QUOTE)
+                .quote(vec![dcl_pool_id(hop)], hop.token_in.clone(), hop.token_out.clone(), amount, None),
+        }
+    }
+
+    /// Reads the pool of one hop.
+    pub fn get_pool(&self, hop: &Hop) -> Promise {
+        match self {
+            Venue::RefV2 { contract_id } | Venue::Jumbo { contract_id } | Venue::RefV2Instant { contract_id } => ref_contract::ext(contract_id.clone())
+                .with_static_gas(GAS_FOR_POOL_QUOTE)
+                .get_pool(hop.pool_id),
+            Venue::RefDcl { contract_id } => ref_dcl::ext(contract_id.clone())
+                .with_static_gas(GAS_FOR_POOL_QUOTE)
+                .get_pool(dcl_pool_id(hop)),
+        }
+    }
+
+    /// `msg` of the ft_transfer_call sending the input of a batch to the venue. `echo`
+    /// comes back as the `msg` of the transfer of the output, when the venue sends it.
+    pub fn deposit_message(&self, route: &[Hop], amount_in: u128, min_amount_out: u128, echo: String) -> String {
+        match self {
+            // a plain deposit, swapped by a separate call
+            Venue::RefV2 { .. } | Venue::Jumbo { .. } => "".to_string(),
+            Venue::RefDcl { .. } => {
+                let message = DclMessage::Swap {
+                    pool_ids: route.iter().map(dcl_pool_id).collect(),
+                    output_token: route[route.len() - 1].token_out.clone(),
+                    min_output_amount: U128(min_amount_out),
+                    // wNEAR is credited as wNEAR
+                    skip_unwrap_near: Some(true),
+                    client_echo: Some(echo),
+                };
+                serde_json::to_string(&message).unwrap()
+            }
+            Venue::RefV2Instant { .. } => {
+                let message = RefExecuteMessage {
+                    actions: create_ref_message(route, amount_in, min_amount_out),
+                    skip_unwrap_near: Some(true),
+                    client_echo: Some(echo),
+                };
+                serde_json::to_string(&message).unwrap()
+            }
+        }
+    }
+
+    /// Gas of the transfer of the input to the venue, with the swap and the transfer
+    /// of the output when the venue makes them.
+    pub fn deposit_gas(&self) -> Gas {
+        if self.keeps_deposits() {
+            GAS_FOR_POOL_DEPOSIT
+        } else {
+            GAS_FOR_POOL_DEPOSIT.saturating_add(GAS_FOR_POOL_SWAP).saturating_add(GAS_FOR_POOL_OUTPUT_TRANSFER)
+        }
+    }
+
+    /// Swaps a deposit along the route.
+    pub fn swap(&self, route: &[Hop], amount_in: u128, min_amount_out: u128) -> Promise {
+        ref_contract::ext(self.deposit_contract_id().clone())
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .with_static_gas(GAS_FOR_POOL_SWAP)
+            .swap(create_ref_message(route, amount_in, min_amount_out))
+    }
+
+    /// Withdraws tokens deposited in the venue back to the contract, wNEAR included,
+    /// which Ref would otherwise unwrap.
+    pub fn withdraw(&self, token_id: AccountId, amount: U128) -> Promise {
+        ref_contract::ext(self.deposit_contract_id().clone())
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .with_static_gas(GAS_FOR_POOL_WITHDRAW)
+            .withdraw(token_id, amount, Some(true))
+    }
+
+    /// Balance of the account deposited in the venue.
+    pub fn deposit_of(&self, account_id: AccountId, token_id: AccountId) -> Promise {
+        ref_contract::ext(self.deposit_contract_id().clone())
+            .with_static_gas(GAS_FOR_POOL_QUOTE)
+            .get_deposit(account_id, token_id)
+    }
+
+    fn deposit_contract_id(&self) -> &AccountId {
+        assert!(self.keeps_deposits(), "The venue does not keep deposits");
+        self.contract_id()
+    }
+}
+
+/// DCL pools are keyed by their two tokens, in order, and their fee, which is the
+/// `pool_id` of the hop.
+fn dcl_pool_id(hop: &Hop) -> String {
+    let (token_x, token_y) = if hop.token_in < hop.token_out {
+        (&hop.token_in, &hop.token_out)
+    } else {
+        (&hop.token_out, &hop.token_in)
+    };
+    format!("{}|{}|{}", token_x, token_y, hop.pool_id)
+}
+
+// One step of a Ref swap. Ref feeds the output of an action without `amount_in` to
+// the next one.
 #[near(serializers = [json])]
 pub struct Action {
-    pool_id: u64,
-    token_in: AccountId,
-    token_out: AccountId,
-    amount_in: U128,
-    min_amount_out: U128,
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_in: Option<U128>,
+    pub min_amount_out: U128,
+}
+
+// Pool description returned by Ref's `get_pool` view
+#[near(serializers = [json])]
+pub struct PoolInfo {
+    pub pool_kind: String,
+    pub token_account_ids: Vec<AccountId>,
+    pub amounts: Vec<U128>,
+    pub total_fee: u32,
+    pub shares_total_supply: U128,
+}
+
+// Pool description returned by DCL's `get_pool` view, other fields are ignored
+#[near(serializers = [json])]
+pub struct DclPoolInfo {
+    pub token_x: AccountId,
+    pub token_y: AccountId,
+    pub total_x: U128,
+    pub total_y: U128,
+}
+
+// Pool of a hop as returned by any venue
+#[near(serializers = [json])]
+#[serde(untagged)]
+pub enum VenuePool {
+    Ref(PoolInfo),
+    Dcl(DclPoolInfo),
+}
+
+impl VenuePool {
+    /// Reserve of the given token held by the pool, `None` if the pool does not trade it.
+    pub fn reserve_of(&self, token_id: &AccountId) -> Option<u128> {
+        match self {
+            VenuePool::Ref(pool) => pool.reserve_of(token_id),
+            VenuePool::Dcl(pool) if &pool.token_x == token_id => Some(pool.total_x.0),
+            VenuePool::Dcl(pool) if &pool.token_y == token_id => Some(pool.total_y.0),
+            VenuePool::Dcl(_) => None,
+        }
+    }
 }
 
+// Quote returned by any venue: Ref returns the amount, DCL an object holding it
+#[near(serializers = [json])]
+#[serde(untagged)]
+pub enum VenueQuote {
+    Amount(U128),
+    Dcl { amount: U128 },
+}
+
+impl VenueQuote {
+    pub fn amount(&self) -> U128 {
+        match self {
+            VenueQuote::Amount(amount) | VenueQuote::Dcl { amount } => *amount,
+        }
+    }
+}
+
+// `msg` of an ft_transfer_call to Ref executing the actions with the tokens sent
+#[near(serializers = [json])]
+pub struct RefExecuteMessage {
+    pub actions: Vec<Action>,
+    pub skip_unwrap_near: Option<bool>,
+    // the output is sent back with ft_transfer_call, with this as msg
+    pub client_echo: Option<String>,
+}
+
+// `msg` of an ft_transfer_call to DCL
+#[near(serializers = [json])]
+pub enum DclMessage {
+    Swap {
+        pool_ids: Vec<String>,
+        output_token: AccountId,
+        min_output_amount: U128,
+        skip_unwrap_near: Option<bool>,
+        client_echo: Option<String>,
+    },
+}
+
+impl PoolInfo {
+    /// Reserve of the given token held by the pool, `None` if the pool does not trade it.
+    pub fn reserve_of(&self, token_id: &AccountId) -> Option<u128> {
+        self.token_account_ids
+            .iter()
+            .position(|token| token == token_id)
+            .and_then(|index| self.amounts.get(index))
+            .map(|amount| amount.0)
+    }
+}
+
+/// Actions swapping `amount_in` along the route. Only the first hop sets its input
+/// and only the last one its least output, intermediate amounts are left to Ref.
 pub fn create_ref_message(
-    pool_id: u64,
-    token_in: AccountId,
-    token_out: AccountId,
+    route: &[Hop],
     amount_in: u128,
     min_amount_out: u128,
 ) -> Vec<Action> {
-    // Create the RefInnerMsg instance
-    let action = Action {
-        pool_id,
-        token_in,
-        token_out,
-        amount_in: U128(amount_in),
-        min_amount_out: U128(min_amount_out),
-    };
-
-    vec![action]
+    let last = route.len() - 1;
+    route
+        .iter()
+        .enumerate()
+        .map(|(index, hop)| Action {
+            pool_id: hop.pool_id,
+            token_in: hop.token_in.clone(),
+            token_out: hop.token_out.clone(),
+            amount_in: (index == 0).then_some(U128(amount_in)),
+            min_amount_out: U128(if index == last { min_amount_out } else { 0 }),
+        })
+        .collect()
 }
 
 // FT transfer interface
@@ -48,9 +278,23 @@ trait FT {
 #[allow(dead_code)]
 #[ext_contract(ref_contract)]
 trait Ref {
+    fn get_pool(&self, pool_id: u64) -> PoolInfo;
+
+    fn get_return(&self, pool_id: u64, token_in: AccountId, amount_in: U128, token_out: AccountId) -> U128;
+
     fn swap(&mut self, actions: Vec<Action>) -> U128;
 
-    fn withdraw(&mut self, token_id: AccountId, amount: U128) -> U128;
+    fn withdraw(&mut self, token_id: AccountId, amount: U128, skip_unwrap_near: Option<bool>);
+
+    fn get_deposit(&self, account_id: AccountId, token_id: AccountId) -> U128;
+}
+
+#[allow(dead_code)]
+#[ext_contract(ref_dcl)]
+trait RefDcl {
+    fn get_pool(&self, pool_id: String) -> DclPoolInfo;
+
+    fn quote(&self, pool_ids: Vec<String>, input_token: AccountId, output_token: AccountId, input_amount: U128, tag: Option<String>) -> VenueQuote;
 }
 
 #[ext_contract(ext_wrap)]
@@ -0,0 +1,62 @@
+use near_sdk::json_types::U128;
+use near_sdk::{env, log, near, AccountId, Promise, PromiseError};
+
+use crate::ext::ext_fungible_token;
+use crate::math::{mul_div, BPS_DENOMINATOR};
+use crate::{Contract, ContractExt, GAS_FOR_FT_TRANSFER, GAS_FOR_RESOLVE_TRANSFER, YOCTO_DEPOSIT};
+
+impl Contract {
+    /// Protocol fee taken on a swap of the given amount. It is the only fee step:
+    /// it is kept on the input side and never deducted again from the output.
+    pub(crate) fn fee_of(&self, amount: u128) -> u128 {
+        mul_div(amount, self.fees.into(), BPS_DENOMINATOR)
+    }
+
+    /// Amount of a swap left once the protocol fee is taken.
+    pub(crate) fn amount_after_fees(&self, amount: u128) -> u128 {
+        amount - self.fee_of(amount)
+    }
+
+    /// Adds the fee of a settled batch to the ledger of the token it was taken in.
+    pub(crate) fn internal_accrue_fee(&mut self, token_id: AccountId, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let accrued = self.accrued_fees.get(&token_id).map_or(0, |fees| fees.0);
+        self.accrued_fees.insert(token_id, U128(accrued.checked_add(amount).expect("Overflow")));
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn get_accrued_fees(&self) -> Vec<(AccountId, U128)> {
+        self.accrued_fees.iter().map(|(token_id, fees)| (token_id.clone(), *fees)).collect()
+    }
+
+    // Transfers all the fees accrued in the given token to the receiver
+    #[payable]
+    pub fn claim_fees(&mut self, token_id: AccountId, receiver_id: AccountId) -> Promise {
+        self.assert_owner();
+
+        let amount = self.accrued_fees.remove(&token_id).expect("No fees accrued for this token");
+
+        ext_fungible_token::ext(token_id.clone())
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .ft_transfer(receiver_id, amount, Some("DCA protocol fees".to_string()))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .claim_fees_callback(token_id, amount),
+            )
+    }
+
+    #[private]
+    pub fn claim_fees_callback(&mut self, token_id: AccountId, amount: U128, #[callback_result] call_result: Result<(), PromiseError>,) {
+        if call_result.is_err() {
+            // the transfer did not happen, the fees go back to the ledger
+            log!("Claim of {} {} fees failed", amount.0, token_id);
+            self.internal_accrue_fee(token_id, amount.0);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use near_sdk::json_types::U128;
+use near_sdk::{env, log, near, AccountId, NearToken, Promise, PromiseError};
+
+use crate::events::DcaEvent;
+use crate::batch::{BatchId, BatchStatus};
+use crate::ext::ext_fungible_token;
+use crate::math::{mul_div, BPS_DENOMINATOR};
+use crate::pair::PairId;
+use crate::{page_limit, Contract, ContractExt, GAS_FOR_FT_TRANSFER, GAS_FOR_RESOLVE_TRANSFER, YOCTO_DEPOSIT};
+
+// Reward of the accounts triggering batches with `swap`, and the limits keeping them
+// from wasting batches
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeeperConfig {
+    // share of the protocol fee of a settled batch paid to the account that triggered
+    // it, in basis points of the fee
+    pub bounty_bps: u16,
+    // time, in nanoseconds, before anyone but the owner can start another batch of
+    // the same pair and direction
+    pub cooldown: u64,
+    // whether any account can swap, or only the keepers of the registry
+    pub open: bool,
+    // least bond, in yoctoNEAR, a registered keeper holds to swap
+    pub min_bond: U128,
+    // time, in nanoseconds, a keeper leaving the registry waits before withdrawing its
+    // bond, so batches it started can still be looked at and slashed
+    pub unbonding_period: u64,
+    // most taken from the bond of a keeper for one failed batch, in yoctoNEAR
+    pub max_slash: U128,
+}
+
+impl Default for KeeperConfig {
+    fn default() -> Self {
+        Self {
+            bounty_bps: 0,
+            cooldown: 60_000_000_000,
+            open: true,
+            min_bond: U128(NearToken::from_near(10).as_yoctonear()),
+            unbonding_period: 86_400_000_000_000,
+            max_slash: U128(NearToken::from_near(1).as_yoctonear()),
+        }
+    }
+}
+
+// Keeper allowed by the owner, with its bond and the record of the batches it started
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keeper {
+    pub account_id: AccountId,
+    pub bond: U128,
+    // total taken from the bond by the owner
+    pub slashed: U128,
+    // set when the keeper leaves or is removed, it can no longer swap
+    pub unbonding_since: Option<u64>,
+    pub batches_started: u64,
+    pub batches_settled: u64,
+    pub batches_failed: u64,
+    pub last_batch_timestamp: u64,
+    // batches started by the keeper that neither settled nor failed yet
+    pub pending_batches: Vec<BatchId>,
+}
+
+impl Contract {
+    /// Checks that the caller can start a batch of the pair in the given direction now.
+    pub(crate) fn check_keeper(&self, keeper: &AccountId, pair_id: PairId, reverse: bool) -> Result<(), &'static str> {
+        if keeper == &self.owner {
+            return Ok(());
+        }
+        if !self.keeper_config.open {
+            let registered = self.keepers.get(keeper).is_some_and(|keeper| keeper.unbonding_since.is_none());
+            if !registered {
+                return Err("Only registered keepers can swap");
+            }
+            if self.keepers.get(keeper).unwrap().bond.0 < self.keeper_config.min_bond.0 {
+                return Err("The bond of the keeper is below the minimum");
+            }
+        }
+        let last_batch = self.last_batch_timestamps.get(&(pair_id, reverse)).copied().unwrap_or(0);
+        if last_batch != 0 && env::block_timestamp() < last_batch.saturating_add(self.keeper_config.cooldown) {
+            return Err("A batch of this pair was started too recently");
+        }
+        Ok(())
+    }
+
+    /// Counts a batch started by the keeper, if it is in the registry.
+    pub(crate) fn internal_record_batch_started(&mut self, keeper: &AccountId, batch_id: BatchId) {
+        if let Some(keeper) = self.keepers.get_mut(keeper) {
+            keeper.batches_started += 1;
+            keeper.last_batch_timestamp = env::block_timestamp();
+            keeper.pending_batches.push(batch_id);
+        }
+    }
+
+    /// Counts the outcome of a batch started by the keeper, if it is in the registry.
+    pub(crate) fn internal_record_batch_outcome(&mut self, keeper: &AccountId, batch_id: BatchId, settled: bool) {
+        if let Some(keeper) = self.keepers.get_mut(keeper) {
+            keeper.pending_batches.retain(|pending| *pending != batch_id);
+            if settled {
+                keeper.batches_settled += 1;
+            } else {
+                keeper.batches_failed += 1;
+            }
+        }
+    }
+
+    /// Part of the fee of a batch paid to the keeper that triggered it.
+    pub(crate) fn keeper_bounty_of(&self, fee: u128) -> u128 {
+        mul_div(fee, self.keeper_config.bounty_bps.into(), BPS_DENOMINATOR)
+    }
+
+    /// Adds a bounty to the rewards the keeper can claim in the given token.
+    pub(crate) fn internal_reward_keeper(&mut self, keeper: &AccountId, token_id: AccountId, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let reward = self.keeper_rewards.entry(keeper.clone()).or_default().entry(token_id).or_insert(U128(0));
+        reward.0 = reward.0.checked_add(amount).expect("Overflow");
+    }
+}
+
+#[near]
+impl Contract {
+    #[payable]
+    pub fn set_keeper_config(&mut self, config: KeeperConfig) {
+        self.assert_owner();
+        assert!(u128::from(config.bounty_bps) <= BPS_DENOMINATOR, "Bounty must be at most 10000 basis points");
+        DcaEvent::config_changed("keeper_config", &config);
+        self.keeper_config = config;
+    }
+
+    pub fn get_keeper_config(&self) -> KeeperConfig {
+        self.keeper_config.clone()
+    }
+
+    // Allows the account to join the registry of keepers
+    #[payable]
+    pub fn add_keeper(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        match self.keepers.get_mut(&account_id) {
+            // a keeper leaving can be taken back with its bond and record
+            Some(keeper) => {
+                assert!(keeper.unbonding_since.is_some(), "Keeper already exists");
+                keeper.unbonding_since = None;
+            }
+            None => {
+                self.keepers.insert(account_id.clone(), Keeper {
+                    account_id: account_id.clone(),
+                    bond: U128(0),
+                    slashed: U128(0),
+                    unbonding_since: None,
+                    batches_started: 0,
+                    batches_settled: 0,
+                    batches_failed: 0,
+                    last_batch_timestamp: 0,
+                    pending_batches: Vec::new(),
+                });
+            }
+        }
+        DcaEvent::config_changed("keeper", &self.keepers[&account_id]);
+    }
+
+    // Stops the keeper from swapping, its bond can be withdrawn after the unbonding period
+    #[payable]
+    pub fn remove_keeper(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        let keeper = self.keepers.get_mut(&account_id).expect("Keeper does not exist");
+        keeper.unbonding_since.get_or_insert(env::block_timestamp());
+        DcaEvent::config_changed("keeper", keeper);
+    }
+
+    // Takes part of the bond of a keeper for a batch it started that failed, e.g. on
+    // purpose, and sends it to the owner. Each failed batch is slashed at most once,
+    // by at most max_slash.
+    #[payable]
+    pub fn slash_keeper(&mut self, account_id: AccountId, batch_id: BatchId, amount: U128, reason: String) -> Promise {
+        self.assert_owner();
+        assert!(amount.0 <= self.keeper_config.max_slash.0, "Amount exceeds the most slashed for a batch");
+        let batch = self.batches.get_mut(&batch_id).expect("Batch does not exist");
+        assert_eq!(batch.status, BatchStatus::Failed, "Only failed batches are slashed");
+        assert_eq!(batch.keeper, account_id, "The batch was started by another account");
+        assert_eq!(batch.slashed.0, 0, "The batch was already slashed");
+        batch.slashed = amount;
+
+        let keeper = self.keepers.get_mut(&account_id).expect("Keeper does not exist");
+        keeper.bond = U128(keeper.bond.0.checked_sub(amount.0).expect("Amount exceeds the bond"));
+        keeper.slashed = U128(keeper.slashed.0 + amount.0);
+
+        DcaEvent::KeeperSlashed { keeper: &account_id, batch_id, amount, reason: &reason }.emit();
+        Promise::new(self.owner.clone()).transfer(NearToken::from_yoctonear(amount.0))
+    }
+
+    // Adds the attached NEAR to the bond of the caller, who must be allowed by the owner
+    #[payable]
+    pub fn deposit_keeper_bond(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let keeper = self.keepers.get_mut(&account_id).expect("Keeper does not exist");
+        assert!(keeper.unbonding_since.is_none(), "Keeper is leaving the registry");
+        let amount = U128(env::attached_deposit().as_yoctonear());
+        keeper.bond = U128(keeper.bond.0 + amount.0);
+        DcaEvent::KeeperBondDeposited { keeper: &account_id, amount, bond: keeper.bond }.emit();
+        keeper.bond
+    }
+
+    // Leaves the registry, the bond can be withdrawn after the unbonding period
+    pub fn unregister_keeper(&mut self) {
+        let account_id = env::predecessor_account_id();
+        let keeper = self.keepers.get_mut(&account_id).expect("Keeper does not exist");
+        if keeper.unbonding_since.is_none() {
+            keeper.unbonding_since = Some(env::block_timestamp());
+            DcaEvent::KeeperUnregistered { keeper: &account_id, unbonding_since: env::block_timestamp() }.emit();
+        }
+    }
+
+    // Returns the bond of a keeper that left the registry, once the unbonding period
+    // passed and its batches are done. Batches still in flight after the unbonding
+    // period, e.g. stuck ones, do not hold the bond back.
+    pub fn withdraw_keeper_bond(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let keeper = self.keepers.get(&account_id).expect("Keeper does not exist");
+        let unbonding_since = keeper.unbonding_since.expect("Keeper must leave the registry first");
+        let now = env::block_timestamp();
+        assert!(now >= unbonding_since.saturating_add(self.keeper_config.unbonding_period), "Bond is still unbonding");
+        let in_flight = keeper.pending_batches.iter()
+            .filter_map(|batch_id| self.batches.get(batch_id))
+            .any(|batch| now < batch.timestamp.saturating_add(self.keeper_config.unbonding_period));
+        assert!(!in_flight, "Batches of the keeper are still in flight");
+
+        let bond = self.keepers.remove(&account_id).unwrap().bond;
+        DcaEvent::KeeperBondWithdrawn { keeper: &account_id, amount: bond }.emit();
+        Promise::new(account_id).transfer(NearToken::from_yoctonear(bond.0))
+    }
+
+    pub fn get_keeper(&self, account_id: AccountId) -> Option<Keeper> {
+        self.keepers.get(&account_id).cloned()
+    }
+
+    // Keepers of the registry with their bond and record, paginated
+    pub fn get_keepers(&self, from_index: Option<u32>, limit: Option<u32>) -> Vec<Keeper> {
+        self.keepers.values()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(page_limit(limit) as usize)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_keeper_rewards(&self, account_id: AccountId) -> HashMap<AccountId, U128> {
+        self.keeper_rewards.get(&account_id).cloned().unwrap_or_default()
+    }
+
+    // Transfers all the bounties the caller earned in the given token
+    #[payable]
+    pub fn claim_keeper_rewards(&mut self, token_id: AccountId) -> Promise {
+        let keeper = env::predecessor_account_id();
+        let rewards = self.keeper_rewards.get_mut(&keeper).expect("No rewards for this account");
+        let amount = rewards.remove(&token_id).expect("No rewards in this token");
+        if rewards.is_empty() {
+            self.keeper_rewards.remove(&keeper);
+        }
+
+        ext_fungible_token::ext(token_id.clone())
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .ft_transfer(keeper.clone(), amount, Some("DCA keeper rewards".to_string()))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .claim_keeper_rewards_callback(keeper, token_id, amount),
+            )
+    }
+
+    #[private]
+    pub fn claim_keeper_rewards_callback(&mut self, keeper: AccountId, token_id: AccountId, amount: U128, #[callback_result] call_result: Result<(), PromiseError>,) {
+        if call_result.is_err() {
+            // the transfer did not happen, the rewards can be claimed again
+            log!("Claim of {} {} keeper rewards by {} failed", amount.0, token_id, keeper);
+            self.internal_reward_keeper(&keeper, token_id, amount.0);
+        }
+    }
+}
@@ -1,44 +1,150 @@
 // Find all our documentation at https://docs.near.org
 use near_sdk::json_types::U128;
+use near_sdk::store::{IterableMap, LookupMap, TreeMap};
 use near_sdk::{
- AccountId, near, PanicOnDefault, env, Promise, NearToken, log, Gas, PromiseError, near_bindgen
+ AccountId, near, PanicOnDefault, env, Promise, NearToken, Gas, PromiseError, BorshStorageKey
 };
 use std::collections::HashMap;
-use ext::{create_ref_message, ext_fungible_token, ext_wrap, ref_contract};
+use ext::{ext_fungible_token, ext_wrap, Venue, VenuePool, VenueQuote};
+use math::{min_amount_out, mul_div, price_impact_bps, BPS_DENOMINATOR};
+use schedule::{DueKey, PendingSwaps};
+use batch::{Batch, BatchId, BatchStatus};
+use pair::{Pair, PairId, DEFAULT_PAIR_ID};
+use position::{Position, PositionId};
+use keeper::{Keeper, KeeperConfig};
+use stats::UserStats;
+use metrics::Metrics;
+use events::DcaEvent;
 
 pub const GAS_FOR_FT_TRANSFER: Gas = Gas::from_gas(20_000_000_000_000);
 pub const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas::from_gas(20_000_000_000_000);
 pub const YOCTO_DEPOSIT: NearToken = NearToken::from_yoctonear(1);
 
-
+// Gas of the batch pipeline: quote -> deposit -> swap -> withdraw -> settle
+// quotes are views, they only read a pool
+pub const GAS_FOR_POOL_QUOTE: Gas = Gas::from_tgas(5);
+pub const GAS_FOR_POOL_DEPOSIT: Gas = Gas::from_tgas(40);
+// enough for a route of MAX_ROUTE_HOPS actions
+pub const GAS_FOR_POOL_SWAP: Gas = Gas::from_tgas(40);
+pub const GAS_FOR_POOL_WITHDRAW: Gas = Gas::from_tgas(45);
+// transfer of the output back to the contract, by venues that swap on transfer
+pub const GAS_FOR_POOL_OUTPUT_TRANSFER: Gas = Gas::from_tgas(20);
+// own execution. The callbacks from the quote to the settlement also carry the
+// settlement gas of the batch, for crediting its positions and finishing it.
+pub const GAS_FOR_WITHDRAW_CALLBACK: Gas = Gas::from_tgas(10);
+pub const GAS_FOR_REFUND_CALLBACK: Gas = Gas::from_tgas(10);
+// own execution + withdraw + read of the deposit left + withdraw callback
+pub const GAS_FOR_PRE_WITHDRAW_CALLBACK: Gas = Gas::from_tgas(10 + 45 + 5 + 10);
+// own execution + read of the deposit + pre-withdraw callback
+pub const GAS_FOR_SWAP_CALLBACK: Gas = Gas::from_tgas(10 + 5 + 70);
+// own execution + swap + swap callback
+pub const GAS_FOR_TRANSFER_CALLBACK: Gas = Gas::from_tgas(10 + 40 + 85);
+// own execution only, on venues that swap the input as it is transferred
+pub const GAS_FOR_SWAP_TRANSFER_CALLBACK: Gas = Gas::from_tgas(10);
+// own execution + deposit + transfer callback, once the last hop is quoted. Venues
+// swapping on transfer need 40 + 40 + 20 for the deposit and 10 for the callback.
+pub const GAS_FOR_QUOTE_CALLBACK: Gas = Gas::from_tgas(10 + 40 + 135);
+// own execution + the quotes of one more hop
+pub const GAS_FOR_QUOTE_HOP: Gas = Gas::from_tgas(5 + 3 * 5);
+// own execution of swap, with its receipts, + the quotes of the first hop
+pub const GAS_FOR_SWAP: Gas = Gas::from_tgas(50 + 3 * 5);
+// own execution of swap for each position of the batch: locking it and moving it out
+// of the due index
+pub const GAS_FOR_BATCH_POSITION: Gas = Gas::from_tgas(2);
+// most gas a transaction can attach, and so the whole pipeline of a batch
+pub const MAX_TRANSACTION_GAS: Gas = Gas::from_tgas(300);
+// crediting one position of a batch, until settlements measure it
+pub const DEFAULT_GAS_PER_POSITION: Gas = Gas::from_tgas(3);
+pub const MIN_GAS_PER_POSITION: Gas = Gas::from_tgas(1);
+// kept by a settlement step to finish the batch or hand over to the next step
+pub const GAS_FOR_SETTLE_RESERVE: Gas = Gas::from_tgas(25);
+
+// Least age, in nanoseconds, of a pending or swapped batch the owner can fail
+pub const MIN_STUCK_BATCH_AGE: u64 = 3_600_000_000_000;
+
+// Slippage accepted by users that do not set their own, in basis points
+pub const DEFAULT_MAX_SLIPPAGE_BPS: u16 = 100;
+
+// Items returned by paginated views without a limit, and at most with one
+pub const DEFAULT_PAGE_LIMIT: u32 = 50;
+pub const MAX_PAGE_LIMIT: u32 = 100;
+
+
+pub mod batch;
+pub mod events;
 pub mod ext;
+pub mod fees;
+pub mod keeper;
+pub mod math;
+pub mod metrics;
+pub mod migrate;
+pub mod pair;
+pub mod position;
+pub mod receiver;
+pub mod schedule;
+pub mod stats;
+
+// Prefixes of the persistent collections stored in the contract state
+#[derive(BorshStorageKey)]
+#[near(serializers = [borsh])]
+pub enum StorageKey {
+    Positions,
+    Due,
+    Batches,
+    Dust,
+    AccruedFees,
+    AccountPositions,
+    Pairs,
+    KeeperRewards,
+    LastBatchTimestamps,
+    Keepers,
+    UserStats,
+    PoolLocks,
+}
 
 // Define the contract structure
-#[near(contract_state, serializers = [json, borsh])]
+#[near(contract_state)]
 #[derive(PanicOnDefault)]
 pub struct Contract {
-    pub users: HashMap<AccountId, User>,
-    pub user_addresses: Vec<AccountId>,
+    pub positions: IterableMap<PositionId, Position>,
+    // ids of the positions of each account
+    pub account_positions: LookupMap<AccountId, Vec<PositionId>>,
+    pub next_position_id: PositionId,
+    // positions ready to be batched, grouped by pair and direction and ordered by
+    // the time their next swap is due
+    pub due: TreeMap<DueKey, ()>,
+    // markets positions can be opened on, managed by the owner
+    pub pairs: IterableMap<PairId, Pair>,
+    pub next_pair_id: PairId,
+    pub batches: LookupMap<BatchId, Batch>,
+    pub next_batch_id: BatchId,
+    // rounding left over when splitting the output of a batch, per token.
+    // It is added to the output of the next batch of the same token.
+    pub dust: IterableMap<AccountId, U128>,
+    // protocol fees kept from settled batches, per token, until claimed by the owner
+    pub accrued_fees: IterableMap<AccountId, U128>,
+    // bounties earned by keepers triggering batches, per keeper and token, until claimed
+    pub keeper_rewards: LookupMap<AccountId, HashMap<AccountId, U128>>,
+    // when the last batch of each pair and direction was started
+    pub last_batch_timestamps: LookupMap<(PairId, bool), u64>,
+    // batch moving each token through the contract's deposit on a venue, by venue
+    // contract and token. A withdraw is confirmed from the change of the deposit, so
+    // only one batch at a time can use it.
+    pub pool_locks: LookupMap<(AccountId, AccountId), BatchId>,
+    pub keeper_config: KeeperConfig,
+    // keepers allowed by the owner, the only ones that can swap unless keepers are open
+    pub keepers: IterableMap<AccountId, Keeper>,
+    // totals and latest fills of the swaps of each account, kept across withdrawals
+    pub user_stats: LookupMap<AccountId, UserStats>,
+    pub metrics: Metrics,
+    // measured gas of crediting one position of a batch, which sizes the batches
+    pub gas_per_position: Gas,
+    // most positions in a batch, which can hold fewer when the gas attached to swap
+    // does not pay for crediting them
     pub batch_swap_threshold: u8,
-    pub token_address: AccountId,
     pub owner: AccountId,
     pub fees: u8,
     pub wrap_account: AccountId,
-    pub pool_id: u16,
-    pub pool_address: AccountId,
-}
-
-#[near(serializers = [json, borsh])]
-#[derive(Clone)]
-pub struct User {
-    pub wallet: AccountId,
-    pub amount_per_swap: U128,
-    pub swap_interval: u64,
-    pub last_swap_timestamp: u64,
-    pub total_swapped: U128,
-    pub amount: U128,
-    pub pause: bool,
-    pub reverse: bool,
 }
 
 // Define the default, which automatically initializes the contract
@@ -46,140 +152,148 @@ pub struct User {
 impl Contract {
     #[init]
     #[private]
-    pub fn init(token_address: AccountId, owner: AccountId, fees: u8, wrap_account: AccountId, pool_id: u16, pool_address: AccountId) -> Self {
-        Self {
-            
-            users: HashMap::new(),
-            user_addresses: Vec::new(),
+    pub fn init(token_address: AccountId, owner: AccountId, fees: u8, wrap_account: AccountId, pool_id: u64, pool_address: AccountId) -> Self {
+        let mut contract = Self {
+            positions: IterableMap::new(StorageKey::Positions),
+            account_positions: LookupMap::new(StorageKey::AccountPositions),
+            next_position_id: 0,
+            due: TreeMap::new(StorageKey::Due),
+            pairs: IterableMap::new(StorageKey::Pairs),
+            next_pair_id: 0,
+            batches: LookupMap::new(StorageKey::Batches),
+            next_batch_id: 0,
+            dust: IterableMap::new(StorageKey::Dust),
+            accrued_fees: IterableMap::new(StorageKey::AccruedFees),
+            keeper_rewards: LookupMap::new(StorageKey::KeeperRewards),
+            last_batch_timestamps: LookupMap::new(StorageKey::LastBatchTimestamps),
+            pool_locks: LookupMap::new(StorageKey::PoolLocks),
+            keeper_config: KeeperConfig::default(),
+            keepers: IterableMap::new(StorageKey::Keepers),
+            user_stats: LookupMap::new(StorageKey::UserStats),
+            metrics: Metrics::default(),
+            gas_per_position: DEFAULT_GAS_PER_POSITION,
             batch_swap_threshold: 10, // Adjust threshold as needed
-            token_address,
             owner,
             fees,
             wrap_account,
-            pool_id,
-            pool_address,
-        }
+        };
+        // the first pair buys the token with wNEAR on a Ref pool, it is the default one
+        contract.internal_add_pair(contract.wrap_account.clone(), token_address, Venue::RefV2 { contract_id: pool_address }, pool_id);
+        contract
     }
 
     #[payable]
-    pub fn register_user(&mut self, amount_per_swap: U128, swap_interval: u64, reverse: Option<bool>) {
+    pub fn create_position(&mut self, pair_id: Option<PairId>, amount_per_swap: U128, swap_interval: u64, reverse: Option<bool>, max_slippage_bps: Option<u16>) -> PositionId {
         // get attached deposit
         let amount = env::attached_deposit();
-        assert!(amount.as_yoctonear() > 0, "Deposit must be greater than 0");
-
-        // user must not exist
-        assert!(!self.users.contains_key(&env::signer_account_id()), "User already exists");
 
         // check if reverse is not set then set it to false
-        let reverse_flag = match reverse {
-            Some(reverse) => reverse,
-            None => false
-        };
+        let reverse_flag = reverse.unwrap_or_default();
+        let pair_id = pair_id.unwrap_or(DEFAULT_PAIR_ID);
+        let max_slippage_bps = max_slippage_bps.unwrap_or(DEFAULT_MAX_SLIPPAGE_BPS);
+
+        if let Err(error) = self.check_position_settings(pair_id, amount_per_swap, max_slippage_bps) {
+            env::panic_str(error);
+        }
 
-        // if reverse is false
-        if reverse_flag == false {
+        let (token_sold, _) = self.internal_get_pair(pair_id).tokens(reverse_flag);
+        let sells_near = token_sold == self.wrap_account;
+        if sells_near {
+            // positions selling wNEAR are funded with the attached deposit
             assert!(amount.as_yoctonear() > amount_per_swap.0, "Deposit must be greater than swap amount");
+        } else {
+            // other tokens are deposited with ft_transfer_call
+            assert!(amount.is_zero(), "Positions selling a token deposit it with ft_transfer_call");
         }
-        
-        let user = User {
-            wallet: env::signer_account_id(),
-            amount_per_swap,
-            swap_interval,
-            last_swap_timestamp: 0,
-            total_swapped: U128(0),
-            amount: amount.as_yoctonear().into(),
-            pause: false,
-            reverse: reverse_flag,
-        };
-        self.users.insert(env::signer_account_id(), user);
-        self.user_addresses.push(env::signer_account_id());
 
-        // wrap the amount
-        ext_wrap::ext(self.wrap_account.clone())
-            .with_static_gas(GAS_FOR_FT_TRANSFER)
-            .with_attached_deposit(NearToken::from_yoctonear(amount.as_yoctonear().into()))
-            .near_deposit();
+        let position_id = self.internal_create_position(env::predecessor_account_id(), pair_id, amount_per_swap, swap_interval, reverse_flag, max_slippage_bps, amount.as_yoctonear());
+
+        if sells_near {
+            // wrap the amount
+            ext_wrap::ext(self.wrap_account.clone())
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .with_attached_deposit(amount)
+                .near_deposit();
+        }
+
+        position_id
     }
 
     #[payable]
-    pub fn topup(&mut self) {
+    pub fn topup(&mut self, position_id: PositionId) {
         let amount = env::attached_deposit();
         assert!(amount.as_yoctonear() > 0, "Deposit must be greater than 0");
-        
-        // user must exist
-        assert!(self.users.contains_key(&env::signer_account_id()), "User does not exist");
-
-        let mut user = self.users.get(&env::signer_account_id()).unwrap().clone();
 
-        user.amount = U128(user.amount.0 + amount.as_yoctonear()); // add amount;
-        self.users.insert(env::signer_account_id(), user.clone());
+        // position must exist and belong to the caller
+        let mut position = self.internal_get_own_position(position_id);
+        let (token_sold, _) = self.position_tokens(&position);
+        assert!(token_sold == self.wrap_account, "Positions selling a token deposit it with ft_transfer_call");
+        position.amount = U128(position.amount.0 + amount.as_yoctonear()); // add amount;
+        DcaEvent::PositionToppedUp { position_id, account_id: &position.wallet, token_id: &token_sold, amount: U128(amount.as_yoctonear()) }.emit();
+        self.internal_save_position(position);
 
         // wrap the amount
         ext_wrap::ext(self.wrap_account.clone())
             .with_static_gas(GAS_FOR_FT_TRANSFER)
-            .with_attached_deposit(NearToken::from_yoctonear(amount.as_yoctonear().into()))
+            .with_attached_deposit(amount)
             .near_deposit();
     }
 
     #[payable]
-    pub fn withdraw_near(&mut self, amount: U128) {
+    pub fn withdraw_near(&mut self, position_id: PositionId, amount: U128) {
         let deposit = env::attached_deposit();
         assert!(deposit.as_yoctonear() == 1, "Deposit must be 1");
-        // user must exist
-        assert!(self.users.contains_key(&env::signer_account_id()), "User does not exist");
-        let mut user = self.users.get(&env::signer_account_id()).unwrap().clone();
+        // position must exist and belong to the caller
+        let mut position = self.internal_get_own_position(position_id);
 
-        // check if user has enough balance
-        assert!(user.amount >= amount, "User does not have enough balance");
+        // check if the position has enough balance, besides a swap in flight
+        let tokens = self.position_tokens(&position);
+        let withdrawable = position.withdrawable(&tokens, &self.wrap_account).expect("Position does not hold wNEAR");
+        assert!(withdrawable >= amount.0, "Position does not have enough balance");
+        let balance = position.balance_mut(&tokens, &self.wrap_account).unwrap();
 
-        let new_amount = user.amount.0.checked_sub(amount.0).expect("Insufficient funds");
-        user.amount = U128(new_amount); // subtract amount;
-        self.users.insert(env::signer_account_id(), user.clone());
+        let new_amount = balance.0.checked_sub(amount.0).expect("Insufficient funds");
+        *balance = U128(new_amount); // subtract amount;
+        DcaEvent::PositionWithdrawn { position_id, account_id: &position.wallet, token_id: &self.wrap_account, amount }.emit();
+        self.internal_save_position(position);
 
         let near_amount: NearToken = NearToken::from_yoctonear(amount.0);
 
-        // wrap the amount
+        // unwrap the amount
         ext_wrap::ext(self.wrap_account.clone())
             .with_static_gas(GAS_FOR_FT_TRANSFER)
             .with_attached_deposit(YOCTO_DEPOSIT)
             .near_withdraw(amount).
-        then(Promise::new(env::signer_account_id()).transfer(near_amount));
+        then(Promise::new(env::predecessor_account_id()).transfer(near_amount));
     }
 
     #[payable]
-    pub fn withdraw_ft(&mut self, amount: U128) {
-        // user must exist
-        assert!(self.users.contains_key(&env::signer_account_id()), "User does not exist");
-        let mut user = self.users.get(&env::signer_account_id()).unwrap().clone();
-        // check if user has enough balance
-        assert!(user.total_swapped >= amount, "User does not have enough balance");
-
-        let new_total_swapped = user.total_swapped.0.checked_sub(amount.0).expect("Amount to withdraw is greater than total swapped");
-        user.total_swapped = U128(new_total_swapped); // subtract amount;
-        self.users.insert(env::signer_account_id(), user.clone());
-
-
-        ext_fungible_token::ext(self.token_address.clone())
+    pub fn withdraw_ft(&mut self, position_id: PositionId, token_id: AccountId, amount: U128) {
+        // position must exist and belong to the caller
+        let mut position = self.internal_get_own_position(position_id);
+        // check if the position has enough balance, besides a swap in flight
+        let tokens = self.position_tokens(&position);
+        let withdrawable = position.withdrawable(&tokens, &token_id).expect("Position does not hold this token");
+        assert!(withdrawable >= amount.0, "Position does not have enough balance");
+        let balance = position.balance_mut(&tokens, &token_id).unwrap();
+
+        let new_balance = balance.0.checked_sub(amount.0).expect("Amount to withdraw is greater than the balance");
+        *balance = U128(new_balance); // subtract amount;
+        DcaEvent::PositionWithdrawn { position_id, account_id: &position.wallet, token_id: &token_id, amount }.emit();
+        self.internal_save_position(position);
+
+        ext_fungible_token::ext(token_id)
             .with_static_gas(GAS_FOR_FT_TRANSFER)
             .with_attached_deposit(YOCTO_DEPOSIT)
             .ft_transfer(
-                env::signer_account_id(),
-                amount.into(),
+                env::predecessor_account_id(),
+                amount,
                 None
             )
         .then(Self::ext(env::current_account_id())
                 .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
                 .callback_post_withdraw_reward(
         ));
-
-        // ext_self::self.token_address
-        //     .with_static_gas(gas)
-        //     .call(ft_transfer_call {
-        //         from: env::current_account_id(),
-        //         to: env::signer_account_id(),
-        //         amount: amount_to_withdraw,
-        //         msg: "".to_string(),
-        //     });
     }
 
     #[private]
@@ -188,269 +302,397 @@ impl Contract {
     }
 
     #[payable]
-    pub fn pause(&mut self) {
-        // user must exist
-        assert!(self.users.contains_key(&env::signer_account_id()), "User does not exist");
-
-        let mut user = self.users.get(&env::signer_account_id()).unwrap().clone();
-
-        assert!(!user.pause, "User is already paused");
-        user.pause = true;
-
-        self.users.insert(env::signer_account_id(), user.clone());
+    pub fn pause(&mut self, position_id: PositionId) {
+        // position must exist and belong to the caller
+        let mut position = self.internal_get_own_position(position_id);
+
+        assert!(!position.pause, "Position is already paused");
+        position.pause = true;
+        DcaEvent::PositionPaused { position_id, account_id: &position.wallet }.emit();
+        self.internal_save_position(position);
     }
 
     #[payable]
-    pub fn resume(&mut self) {
-        // user must exist
-        assert!(self.users.contains_key(&env::signer_account_id()), "User does not exist");
-
-        let mut user = self.users.get(&env::signer_account_id()).unwrap().clone();
-
-        assert!(user.pause, "User is not paused");
-        user.pause = false;
-
-        self.users.insert(env::signer_account_id(), user.clone());
+    pub fn resume(&mut self, position_id: PositionId) {
+        // position must exist and belong to the caller
+        let mut position = self.internal_get_own_position(position_id);
+
+        assert!(position.pause, "Position is not paused");
+        position.pause = false;
+        DcaEvent::PositionResumed { position_id, account_id: &position.wallet }.emit();
+        self.internal_save_position(position);
     }
 
     #[payable]
-    pub fn remove_user(&mut self) {
+    pub fn close_position(&mut self, position_id: PositionId) {
         let deposit = env::attached_deposit();
         assert!(deposit.as_yoctonear() == 1, "Deposit must be 1");
-        // user must exist
-        assert!(self.users.contains_key(&env::signer_account_id()), "User does not exist");
-
-        // withdraw all funds
-        self.withdraw_near(self.users.get(&env::signer_account_id()).unwrap().amount);
-        self.withdraw_ft(self.users.get(&env::signer_account_id()).unwrap().total_swapped);
+        // position must exist and belong to the caller
+        let position = self.internal_get_own_position(position_id);
+        assert!(position.in_flight.is_none(), "Position is part of a batch in flight");
+
+        // withdraw all funds, wNEAR is unwrapped
+        let (token_sold, token_bought) = self.position_tokens(&position);
+        for (token_id, balance) in [(token_sold, position.amount), (token_bought, position.total_swapped)] {
+            if balance.0 == 0 {
+                continue;
+            }
+            if token_id == self.wrap_account {
+                self.withdraw_near(position_id, balance);
+            } else {
+                self.withdraw_ft(position_id, token_id, balance);
+            }
+        }
 
-        // remove user from users map
-        self.users.remove(&env::signer_account_id());
+        // remove the position from the positions map, its account and the due index
+        self.internal_remove_position(position_id);
+        DcaEvent::PositionClosed { position_id, account_id: &position.wallet }.emit();
     }
 
     #[payable]
-    pub fn change_swap_interval(&mut self, swap_interval: u64) {
-        // user must exist
-        assert!(self.users.contains_key(&env::signer_account_id()), "User does not exist");
+    pub fn change_swap_interval(&mut self, position_id: PositionId, swap_interval: u64) {
+        // position must exist and belong to the caller
+        let mut position = self.internal_get_own_position(position_id);
+        position.swap_interval = swap_interval;
+        self.internal_save_position(position);
+    }
 
-        let mut user = self.users.get(&env::signer_account_id()).unwrap().clone();
-        user.swap_interval = swap_interval;
-        self.users.insert(env::signer_account_id(), user.clone());
+    #[payable]
+    pub fn change_max_slippage(&mut self, position_id: PositionId, max_slippage_bps: u16) {
+        assert!(u128::from(max_slippage_bps) <= BPS_DENOMINATOR, "Slippage must be at most 10000 basis points");
+        // position must exist and belong to the caller
+        let mut position = self.internal_get_own_position(position_id);
+        position.max_slippage_bps = max_slippage_bps;
+        self.internal_save_position(position);
     }
 
-    pub fn can_swap(&self, reverse: Option<bool>) -> bool {
-        
+    pub fn can_swap(&self, pair_id: Option<PairId>, reverse: Option<bool>) -> bool {
         // check if reverse is not set then set it to false
-        let reverse_flag = match reverse {
-            Some(reverse) => reverse,
-            None => false
-        };
+        let reverse_flag = reverse.unwrap_or_default();
+        let pair_id = pair_id.unwrap_or(DEFAULT_PAIR_ID);
 
-        for (_, user) in self.users.iter() {
-            // check if user has to swap and if it is not paused
-            // create a mutable copy of the user
-            let mut _tmp_user = user.clone();
-
-            if env::block_timestamp() >= user.last_swap_timestamp + user.swap_interval && user.pause == false && user.reverse == reverse_flag {
-                // check if amount per swap is bigger than amount otherwise pause the user
-                if user.amount < user.amount_per_swap {
-                    // tmp_user.pause = true;
-                    // self.users.insert(user.wallet.clone(), tmp_user.clone());
-                    continue;
-                }
-            
-                return true;
-            }
-        }
-
-        return false;
-        
+        // the due index only holds active positions with enough balance
+        self.pairs.get(&pair_id).is_some_and(|pair| pair.enabled && self.is_pair_pool_free(pair)) && self.has_due_positions(pair_id, reverse_flag)
     }
 
+    // Anyone can start a batch of due positions, the caller earns the keeper bounty
+    // once it settles
     #[payable]
-    pub fn swap(&mut self, reverse: Option<bool>) {
-        assert_eq!(env::signer_account_id(), self.owner);
-        // iterate over all users
-        // check if timestamp is greater than last_swap_timestamp + swap_interval
-        // if yes add them to the batch
-
+    pub fn swap(&mut self, pair_id: Option<PairId>, reverse: Option<bool>) {
+        let keeper = env::predecessor_account_id();
         // check if reverse is not set then set it to false
-        let reverse_flag = match reverse {
-            Some(reverse) => reverse,
-            None => false
-        };
-
-        let mut batch_amount:U128 = U128(0);
-        let mut batch_users:Vec<AccountId> = Vec::new();
-        
-        for (_, user) in self.users.iter() {
-            // check if user has to swap and if it is not paused
-            // create a mutable copy of the user
-            let mut _tmp_user = user.clone();
-
-            if env::block_timestamp() >= user.last_swap_timestamp + user.swap_interval && user.pause == false && user.reverse == reverse_flag {
-                // check if amount per swap is bigger than amount otherwise pause the user
-                if user.amount < user.amount_per_swap {
-                    // tmp_user.pause = true;
-                    // self.users.insert(user.wallet.clone(), tmp_user.clone());
-                    continue;
-                }
-                
-                // check if the batch is full
-                if batch_users.len() >= self.batch_swap_threshold.into() {
-                    break;
-                }
-
-                // add to batch
-                batch_amount = U128(batch_amount.0 + user.amount_per_swap.0);
-                batch_users.push(user.wallet.clone());
+        let reverse_flag = reverse.unwrap_or_default();
+        let pair = self.internal_get_pair(pair_id.unwrap_or(DEFAULT_PAIR_ID));
+        assert!(pair.enabled, "Pair is disabled");
+        assert!(self.is_pair_pool_free(&pair), "Another batch is using the pool deposits of the pair");
+        if let Err(error) = self.check_keeper(&keeper, pair.id, reverse_flag) {
+            env::panic_str(error);
+        }
 
-            }
+        // the batch takes as many due positions as the attached gas pays for, so it
+        // never runs out of gas halfway, up to the batch threshold
+        let max_positions = self.max_batch_positions(&pair, env::prepaid_gas());
+        assert!(max_positions > 0, "Attach at least {} to swap", self.batch_gas(&pair, 1));
+        let mut batch_positions = self.due_positions(pair.id, reverse_flag, self.batch_swap_threshold.into());
+        assert!(!batch_positions.is_empty(), "No positions are due");
+        // keepers other than the owner take every due position a transaction can pay
+        // for, so small batches cannot hold the pair back for the cooldown
+        if keeper != self.owner {
+            let full_batch = batch_positions.len().min(self.max_batch_positions(&pair, MAX_TRANSACTION_GAS));
+            assert!(max_positions >= full_batch, "Attach at least {} to swap", self.batch_gas(&pair, full_batch));
         }
+        batch_positions.truncate(max_positions);
+
+        let (batch_amount, batch_amount_total) = self.internal_batch_amounts(&batch_positions);
+        // the smallest swap of the batch gives the reference price the batch is compared to
+        let reference_amount = batch_positions.iter()
+            .map(|position_id| self.amount_after_fees(self.positions.get(position_id).unwrap().amount_per_swap.0))
+            .min()
+            .unwrap();
+
+        let batch_id = self.internal_create_batch(pair.id, reverse_flag, keeper.clone(), batch_positions.clone(), batch_amount, batch_amount_total);
+        self.internal_record_batch_started(&keeper, batch_id);
+        DcaEvent::BatchStarted {
+            batch_id,
+            pair_id: pair.id,
+            reverse: reverse_flag,
+            keeper: &keeper,
+            positions: &batch_positions,
+            amount: batch_amount,
+            amount_in: batch_amount_total,
+        }.emit();
+        // the positions are reserved until the batch settles or fails
+        self.internal_lock_positions(&batch_positions, batch_id);
+        // and the pool deposits of its tokens until its output is withdrawn
+        self.internal_lock_pool(batch_id, pair.token_in.clone());
+        self.internal_lock_pool(batch_id, pair.token_out.clone());
+        self.last_batch_timestamps.insert((pair.id, reverse_flag), env::block_timestamp());
+        self.metrics.last_batch_timestamp = env::block_timestamp();
+
+        // quote the whole batch and the reference amount along the route, and look at
+        // its pools, before sending any funds
+        self.internal_quote_hop(batch_id, 0, batch_amount_total, U128(reference_amount), U128(reference_amount));
+    }
 
-        // check if batch is empty
-        if batch_users.len() == 0 {
+    #[private]
+    pub fn pool_quote_callback(&mut self, batch_id: BatchId, hop: u8, reference_amount: U128, #[callback_result] batch_quote: Result<VenueQuote, PromiseError>, #[callback_result] reference_quote: Result<VenueQuote, PromiseError>, #[callback_result] pool: Result<VenuePool, PromiseError>,) {
+        assert_eq!(env::promise_results_count(), 3);
+        let batch = self.internal_get_batch(batch_id);
+
+        let (Ok(batch_quote), Ok(reference_quote), Ok(pool)) = (batch_quote, reference_quote, pool) else {
+            self.internal_fail_batch(batch_id, "quote failed");
+            return;
+        };
+        let (batch_quote, reference_quote) = (batch_quote.amount(), reference_quote.amount());
+
+        // the pool must trade both tokens of the hop and hold enough of the output for the quote
+        let route_hop = &batch.route[usize::from(hop)];
+        let trades_tokens = pool.reserve_of(&route_hop.token_in).is_some();
+        let has_liquidity = pool.reserve_of(&route_hop.token_out).is_some_and(|reserve| reserve > batch_quote.0);
+        if !trades_tokens || !has_liquidity {
+            self.internal_fail_batch(batch_id, "pool does not match the batch");
             return;
         }
 
-        let batch_amount_total = batch_amount.0.checked_sub(
-            batch_amount.0.checked_mul(self.fees as u128).unwrap_or(0) / 10000
-        ).unwrap_or(0);
+        // the outputs of this hop are the inputs of the next one
+        if usize::from(hop) + 1 < batch.route.len() {
+            self.internal_quote_hop(batch_id, hop + 1, batch_quote, reference_quote, reference_amount);
+            return;
+        }
 
+        // positions that do not accept the price impact of the whole batch are left out
+        // of it, they stay due and are not charged
+        let price_impact = price_impact_bps(batch.amount_in.0, batch_quote.0, reference_amount.0, reference_quote.0);
+        let (batch_positions, left_out): (Vec<PositionId>, Vec<PositionId>) = batch.positions.into_iter()
+            .partition(|position_id| u128::from(self.positions.get(position_id).unwrap().max_slippage_bps) >= price_impact);
 
-        let target_ft_account;
-        // format the actions
-        if reverse_flag == false {
-            target_ft_account = self.wrap_account.clone();
-        }
-        else {
-            target_ft_account = self.token_address.clone();
+        if batch_positions.is_empty() {
+            self.internal_fail_batch(batch_id, "price impact above the slippage of every position");
+            return;
         }
-
-        ext_wrap::ext(target_ft_account.clone())
-            .with_static_gas(Gas::from_tgas(30))
-            .with_attached_deposit(NearToken::from_yoctonear(1))
-            .ft_transfer_call(
-                self.pool_address.clone(),
-                batch_amount_total.into(),
-                Some("".to_string()),
-            )
-        .then(
-            Self::ext(env::current_account_id())
-                .with_static_gas(Gas::from_tgas(150))
-                .pool_transfer_callback(
-                    batch_users,
-                    batch_amount,
-                    batch_amount_total,
-                    reverse_flag
-        ));
+        // positions left out stay due for the next batch
+        self.internal_release_positions(&left_out);
+
+        // the tightest tolerance of the remaining positions protects the whole batch
+        let max_slippage_bps = batch_positions.iter()
+            .map(|position_id| self.positions.get(position_id).unwrap().max_slippage_bps)
+            .min()
+            .unwrap();
+        let (batch_amount, batch_amount_total) = self.internal_batch_amounts(&batch_positions);
+        let accounts = self.position_accounts(&batch_positions);
+
+        let batch = self.batches.get_mut(&batch_id).unwrap();
+        // with positions left out the quote is prorated to what is actually traded
+        batch.quoted_amount_out = U128(mul_div(batch_quote.0, batch_amount_total.0, batch.amount_in.0));
+        batch.positions = batch_positions;
+        batch.accounts = accounts;
+        batch.amount = batch_amount;
+        batch.amount_in = batch_amount_total;
+        batch.fee = U128(batch_amount.0 - batch_amount_total.0);
+        batch.min_amount_out = U128(min_amount_out(batch_amount_total.0, reference_amount.0, reference_quote.0, max_slippage_bps));
+
+        self.internal_pool_deposit(batch_id);
     }
 
     #[private]
-    pub fn pool_transfer_callback(&mut self, batch_users:Vec<AccountId>, batch_amount:U128, batch_amount_total:u128, reverse: bool, #[callback_result] call_result: Result<String, PromiseError>,) {
+    pub fn pool_transfer_callback(&mut self, batch_id: BatchId, #[callback_result] call_result: Result<String, PromiseError>,) {
         assert_eq!(env::promise_results_count(), 1);
-        if call_result.is_err() {
-            log!("There was an error while swapping");
-            // we should rollback the transaction
-            // self.rollback();
-            
+        let batch = self.internal_get_batch(batch_id);
+        let (token_in, _) = self.batch_tokens(&batch);
+
+        // ft_transfer_call resolves to the amount the venue kept, the rest was refunded
+        let deposited = match call_result {
+            Ok(used) => used.parse::<u128>().unwrap_or(0),
+            Err(_) => 0,
+        };
+
+        if !batch.venue.keeps_deposits() {
+            // the venue swapped the input as it received it, or refunded all of it
+            if deposited == 0 {
+                self.internal_fail_batch(batch_id, "swap on transfer failed");
+            } else if batch.status == BatchStatus::Swapped {
+                // the output came first, it is settled with the gas carried here
+                self.internal_settle_batch(batch_id);
+            } else if batch.status == BatchStatus::Pending {
+                // the output is sent back with ft_transfer_call and settled then
+                let batch = self.batches.get_mut(&batch_id).unwrap();
+                batch.status = BatchStatus::Swapped;
+                batch.swapped_timestamp = Some(env::block_timestamp());
+            }
+            return;
+        }
+        if batch.status != BatchStatus::Pending {
+            // failed as stuck meanwhile, what reached the pool is recovered by the owner
             return;
         }
 
-        let amount = call_result.unwrap();
-        
-        let action;
-        if reverse == false {
-            action = create_ref_message(
-                self.pool_id.into(),
-                self.wrap_account.clone(),
-                self.token_address.clone(),
-                amount.parse::<u128>().unwrap(),
-                0,
-            );
+        if deposited < batch.amount_in.0 {
+            self.internal_fail_batch(batch_id, "transfer to the pool failed");
+            if deposited > 0 {
+                // only part of the batch reached the pool: pull it back so nothing
+                // is left in the contract's pool balance
+                self.internal_pool_refund(batch_id, token_in, U128(deposited));
+            }
+            return;
         }
-        else {
-            action = create_ref_message(
-                self.pool_id.into(),
-                self.token_address.clone(),
-                self.wrap_account.clone(),
-                amount.parse::<u128>().unwrap(),
-                0,
+
+        batch.venue.swap(&batch.route, deposited, batch.min_amount_out.0)
+            .then(
+                Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_SWAP_CALLBACK.saturating_add(batch.settlement_gas))
+                .pool_swap_callback(batch_id)
             );
+    }
+
+    #[private]
+    pub fn pool_swap_callback(&mut self, batch_id: BatchId, #[callback_result] call_result: Result<U128, PromiseError>,) {
+        let batch = self.internal_get_batch(batch_id);
+        let (token_in, _) = self.batch_tokens(&batch);
+        if batch.status != BatchStatus::Pending {
+            // failed as stuck meanwhile, what is left in the pool is recovered by the owner
+            return;
         }
 
-        ref_contract::ext(self.pool_address.clone())
-            .with_attached_deposit(NearToken::from_yoctonear(1))
-            .with_static_gas(Gas::from_tgas(30))
-            .swap(action)
+        let amount_out = match call_result {
+            Ok(amount_out) => amount_out,
+            Err(_) => {
+                // the deposit is still in the pool: bring it back, positions were not charged
+                self.internal_fail_batch(batch_id, "swap failed");
+                self.internal_pool_refund(batch_id, token_in, batch.amount_in);
+                return;
+            }
+        };
+
+        let batch = self.batches.get_mut(&batch_id).unwrap();
+        batch.status = BatchStatus::Swapped;
+        batch.swapped_timestamp = Some(env::block_timestamp());
+        batch.amount_out = amount_out;
+
+        self.internal_pool_withdraw(batch_id);
+    }
+
+    #[private]
+    pub fn pool_pre_withdraw_callback(&mut self, batch_id: BatchId, #[callback_result] deposit: Result<U128, PromiseError>,) {
+        let batch = self.internal_get_batch(batch_id);
+        let (_, token_out) = self.batch_tokens(&batch);
+        let Ok(deposit) = deposit else {
+            // nothing was withdrawn, the batch stays swapped until retry_batch_withdraw
+            DcaEvent::BatchWithdrawFailed { batch_id, token_id: &token_out, amount: batch.amount_out }.emit();
+            return;
+        };
+
+        batch.venue.withdraw(token_out.clone(), batch.amount_out)
+            .then(batch.venue.deposit_of(env::current_account_id(), token_out))
             .then(
                 Self::ext(env::current_account_id())
-                .with_static_gas(Gas::from_tgas(150))
-                .pool_swap_callback(batch_users, batch_amount, batch_amount_total, reverse)
+                    .with_static_gas(GAS_FOR_WITHDRAW_CALLBACK.saturating_add(batch.settlement_gas))
+                    .pool_withdraw_callback(batch_id, deposit),
             );
-
-        
     }
 
     #[private]
-    pub fn pool_swap_callback(&mut self, batch_users:Vec<AccountId>, batch_amount:U128, batch_amount_total:u128, reverse: bool, #[callback_result] call_result: Result<U128, PromiseError>,) -> HashMap<AccountId, u128> {
-        let amount = call_result.unwrap();
-        
-        let token_out;
-        if reverse == false {
-            token_out = self.token_address.clone();
-        }        
-        else {
-            token_out = self.wrap_account.clone();
+    pub fn pool_withdraw_callback(&mut self, batch_id: BatchId, deposit_before: U128, #[callback_result] deposit_after: Result<U128, PromiseError>,) -> HashMap<PositionId, u128> {
+        // Ref resolves the withdraw even when its transfer fails, it then credits the
+        // amount back to the deposit. Only a deposit that dropped by the output shows
+        // the output reached the contract.
+        let batch = self.internal_get_batch(batch_id);
+        let withdrawn = deposit_after.is_ok_and(|deposit_after| deposit_before.0.saturating_sub(deposit_after.0) >= batch.amount_out.0);
+        if !withdrawn {
+            // the output is still in the pool, the batch stays swapped until
+            // retry_batch_withdraw succeeds
+            let (_, token_out) = self.batch_tokens(&batch);
+            DcaEvent::BatchWithdrawFailed { batch_id, token_id: &token_out, amount: batch.amount_out }.emit();
+            return HashMap::new();
         }
 
-        ref_contract::ext(self.pool_address.clone())
-            .with_attached_deposit(NearToken::from_yoctonear(1))
-            .with_static_gas(Gas::from_tgas(30))
-            .withdraw(token_out.clone(), amount);
-
-        // initialize the return value
-        let mut return_value: HashMap<AccountId, u128> = HashMap::new();
-
-        // update last_swap_timestamp, total_swapped and amount for users in the batch
-        for user in batch_users {
-            let mut user_tmp: User = self.users.get(&user.clone()).unwrap().clone();
-            user_tmp.last_swap_timestamp = env::block_timestamp();
-            // get the percentage of total_swapped and amount
-            let target_amount = (((user_tmp.amount_per_swap.0*100) / batch_amount.0) * amount.0)/100;
-            let final_amount = target_amount.checked_sub(target_amount.checked_mul(self.fees as u128).unwrap_or(0) / 10000).unwrap_or(0);
-            user_tmp.total_swapped = U128(user_tmp.total_swapped.0 +target_amount);
-            let new_amount = user_tmp.amount.0.checked_sub(user_tmp.amount_per_swap.0).expect("Insufficient funds");
-            user_tmp.amount = U128(new_amount);
-            self.users.insert(user_tmp.wallet.clone(), user_tmp.clone());
-            // log the swap
-            if reverse == false {
-                log!("<swapLog> {{\"user\": \"{}\", \"source\": \"{}\", \"source_amount\": {}, \"target\": \"{}\", \"target_amount\": \"{}\"}}", user_tmp.wallet.clone(), self.wrap_account, user_tmp.amount_per_swap.0, self.token_address, final_amount);
-            }
-            else {
-                log!("<swapLog> {{\"user\": \"{}\", \"source\": \"{}\", \"source_amount\": {}, \"target\": \"{}\", \"target_amount\": \"{}\"}}", user_tmp.wallet.clone(), self.token_address, user_tmp.amount_per_swap.0, self.wrap_account, final_amount);
-            }
-            // add to return value
-            return_value.insert(user.clone(), user_tmp.total_swapped.0);
+        self.internal_unlock_pool(batch_id);
+        self.internal_settle_batch(batch_id)
+    }
+
+    #[private]
+    pub fn pool_refund_callback(&mut self, batch_id: BatchId, token_id: AccountId, amount: U128, #[callback_result] call_result: Result<(), PromiseError>,) {
+        if call_result.is_err() {
+            DcaEvent::BatchRefundFailed { batch_id, token_id: &token_id, amount }.emit();
         }
+        self.internal_unlock_pool(batch_id);
+    }
+
+    // Re-issues the withdraw of a batch whose output is still in the pool
+    #[payable]
+    pub fn retry_batch_withdraw(&mut self, batch_id: BatchId) {
+        self.assert_owner();
+        let batch = self.internal_get_batch(batch_id);
+        assert_eq!(batch.status, BatchStatus::Swapped, "Batch is not waiting for a withdraw");
+        assert!(batch.venue.keeps_deposits(), "The venue of the batch sends its output itself");
+
+        self.internal_pool_withdraw(batch_id);
+    }
 
-        return_value
+    // Fails a batch left pending on a venue keeping deposits, so its positions are
+    // released and batched again. Its input is at most in the contract's deposit on the
+    // venue, where recover_pool_balance pulls it back once the batch no longer holds it.
+    #[payable]
+    pub fn fail_stuck_batch(&mut self, batch_id: BatchId) {
+        self.assert_owner();
+        let batch = self.internal_get_batch(batch_id);
+        assert_eq!(batch.status, BatchStatus::Pending, "Batch is not pending");
+        assert!(batch.venue.keeps_deposits(), "The input of the batch may have been swapped by the venue");
+        assert!(env::block_timestamp() >= batch.timestamp.saturating_add(MIN_STUCK_BATCH_AGE), "Batch is too recent to be stuck");
+
+        self.internal_fail_batch(batch_id, "stuck");
+    }
+
+    // Settles a batch whose venue swapped its input but never sent the output back, with
+    // the output the owner recovered into the contract. The positions are charged and
+    // credited their share of amount_out.
+    #[payable]
+    pub fn settle_stuck_batch(&mut self, batch_id: BatchId, amount_out: U128) -> HashMap<PositionId, u128> {
+        self.assert_owner();
+        let batch = self.internal_get_batch(batch_id);
+        assert_eq!(batch.status, BatchStatus::Swapped, "Batch is not waiting for its output");
+        assert!(!batch.venue.keeps_deposits(), "The output of the batch is withdrawn with retry_batch_withdraw");
+        assert!(env::block_timestamp() >= batch.timestamp.saturating_add(MIN_STUCK_BATCH_AGE), "Batch is too recent to be stuck");
+
+        self.batches.get_mut(&batch_id).unwrap().amount_out = amount_out;
+        self.internal_settle_batch(batch_id)
+    }
+
+    // Credits the positions of a batch whose settlement did not fit in the gas it was
+    // given. Anyone can call it, the output of the batch is already in the contract.
+    pub fn settle_batch(&mut self, batch_id: BatchId) -> HashMap<PositionId, u128> {
+        assert_eq!(self.internal_get_batch(batch_id).status, BatchStatus::Settling, "Batch is not being settled");
+        self.internal_settle_batch(batch_id)
+    }
+
+    pub fn get_gas_per_position(&self) -> Gas {
+        self.gas_per_position
+    }
+
+    // Pulls tokens left in the contract's balance on a venue back to the contract
+    #[payable]
+    pub fn recover_pool_balance(&mut self, venue: Venue, token_id: AccountId, amount: U128) -> Promise {
+        self.assert_owner();
+        assert!(self.is_pool_free(&venue, &token_id), "A batch is using the pool deposit of the token");
+
+        venue.withdraw(token_id, amount)
     }
 
     #[payable]
     pub fn set_batch_swap_threshold(&mut self, new_threshold: u8) {
-        assert_eq!(env::signer_account_id(), self.owner);
+        self.assert_owner();
         self.batch_swap_threshold = new_threshold;
+        DcaEvent::config_changed("batch_swap_threshold", new_threshold);
     }
 
     pub fn get_batch_swap_threshold(&self) -> u8 {
-        self.batch_swap_threshold.into()
+        self.batch_swap_threshold
     }
 
     #[payable]
     pub fn set_fees(&mut self, new_fees: u8) {
-        assert_eq!(near_sdk::env::signer_account_id(), self.owner);
+        self.assert_owner();
         self.fees = new_fees;
+        DcaEvent::config_changed("fees", new_fees);
     }
 
     pub fn get_fees(&self) -> u8 {
@@ -461,76 +703,59 @@ impl Contract {
         self.wrap_account.clone()
     }
 
-    pub fn get_token_address(&self) -> AccountId {
-        self.token_address.clone()
+    pub fn get_pending_swaps(&self, pair_id: Option<PairId>) -> PendingSwaps {
+        let pair_id = pair_id.unwrap_or(DEFAULT_PAIR_ID);
+        PendingSwaps {
+            forward: self.count_due_positions(pair_id, false),
+            reverse: self.count_due_positions(pair_id, true),
+        }
     }
 
-    pub fn get_user(&self, user: AccountId) -> User {
-        self.users.get(&user).unwrap().clone()
+    pub fn get_dust(&self) -> Vec<(AccountId, U128)> {
+        self.dust.iter().map(|(token_id, dust)| (token_id.clone(), *dust)).collect()
     }
-}
 
-    /*
-    trait that will be used as the callback from the FT contract. When ft_transfer_call is
-    called, it will fire a cross contract call to this marketplace and this is the function
-    that is invoked. 
-*/
-trait FungibleTokenReceiver {
-    fn ft_on_transfer(
-        &mut self,
-        sender_id: AccountId,
-        amount: U128
-    ) -> U128;
-}
+    pub fn get_batch(&self, batch_id: BatchId) -> Option<Batch> {
+        self.batches.get(&batch_id).cloned()
+    }
 
-//implementation of the trait
-#[near_bindgen]
-impl FungibleTokenReceiver for Contract {
-    /// This is how users will fund their FT balances in the contract
-    fn ft_on_transfer(
-        &mut self,
-        sender_id: AccountId,
-        amount: U128
-    ) -> U128 {
-        // get the contract ID which is the predecessor
-        let ft_contract_id = env::predecessor_account_id();
-        // Ensure only the specified FT can be used
-        // check if the predecessor is the FT contract
-        assert_eq!(
-            ft_contract_id, self.token_address, "The FT token accepted is {}", self.token_address
-        );
-        
-        //get the signer which is the person who initiated the transaction
-        let signer_id = env::signer_account_id();
-
-        //make sure that the signer isn't the predecessor. This is so that we're sure
-        //this was called via a cross-contract call
-        assert_ne!(
-            ft_contract_id,
-            signer_id,
-            "ft_on_transfer should only be called via cross-contract call"
-        );
-        //make sure the owner ID is the signer. 
-        assert_eq!(
-            sender_id,
-            signer_id,
-            "sender_id should be signer_id"
-        );
-    
-        
-        // user must exist
-        assert!(self.users.contains_key(&env::signer_account_id()), "User does not exist");
+    // Batches in the order they were started, paginated by batch id
+    pub fn list_batches(&self, from_index: Option<BatchId>, limit: Option<u32>) -> Vec<Batch> {
+        let from_index = from_index.unwrap_or(0).min(self.next_batch_id);
+        let to_index = from_index.saturating_add(page_limit(limit).into()).min(self.next_batch_id);
+        (from_index..to_index).filter_map(|batch_id| self.batches.get(&batch_id).cloned()).collect()
+    }
 
-        let mut user = self.users.get(&env::signer_account_id()).unwrap().clone();
+    pub fn get_batch_count(&self) -> u64 {
+        self.next_batch_id
+    }
+
+    pub fn get_position(&self, position_id: PositionId) -> Position {
+        self.internal_get_position(position_id)
+    }
 
-        user.amount = U128(user.total_swapped.0 + amount.0); // add amount;
-        self.users.insert(env::signer_account_id(), user.clone());
+    // Positions of the account, in the order they were created
+    pub fn get_positions(&self, account_id: AccountId) -> Vec<Position> {
+        self.account_position_ids(&account_id)
+            .into_iter()
+            .map(|position_id| self.internal_get_position(position_id))
+            .collect()
+    }
+}
 
-        // We don't return any FTs to the sender because we're storing all of them in their balance
-        U128(0)
+impl Contract {
+    /// Owner methods check the direct caller, so the owner can be a multisig or a DAO.
+    pub(crate) fn assert_owner(&self) {
+        assert_eq!(env::predecessor_account_id(), self.owner, "Only the owner can call this method");
     }
 }
 
+/// Size of a page of a paginated view, DEFAULT_PAGE_LIMIT unless a limit is given and
+/// never more than MAX_PAGE_LIMIT.
+pub(crate) fn page_limit(limit: Option<u32>) -> u32 {
+    limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT)
+}
+
 /*
  * The rest of this file holds the inline tests for the code above
  * Learn more about Rust tests: https://doc.rust-lang.org/book/ch11-01-writing-tests.html
@@ -538,7 +763,1678 @@ impl FungibleTokenReceiver for Contract {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::batch::BatchStatus;
+    use crate::ext::{create_ref_message, DclPoolInfo, PoolInfo};
+    use crate::pair::Hop;
+    use crate::migrate::{OldContract, OldUser};
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::{testing_env, PromiseOrValue, PromiseResult};
+
+    const ONE_NEAR: u128 = 1_000_000_000_000_000_000_000_000;
+
+    fn context(signer: AccountId, deposit: u128) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id("dca.near".parse().unwrap())
+            .signer_account_id(signer.clone())
+            .predecessor_account_id(signer)
+            .attached_deposit(NearToken::from_yoctonear(deposit));
+        builder
+    }
+
+    // environment of a callback receiving the result of one promise
+    fn callback_env(timestamp: u64) {
+        testing_env!(
+            context("dca.near".parse().unwrap(), 0).block_timestamp(timestamp).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+    }
+
+    fn pool() -> PoolInfo {
+        PoolInfo {
+            pool_kind: "SIMPLE_POOL".to_string(),
+            token_account_ids: vec!["wrap.near".parse().unwrap(), "token.near".parse().unwrap()],
+            amounts: vec![U128(1_000 * ONE_NEAR), U128(1_000 * ONE_NEAR)],
+            total_fee: 30,
+            shares_total_supply: U128(1),
+        }
+    }
+
+    fn ref_venue() -> Venue {
+        Venue::RefV2 { contract_id: "ref.near".parse().unwrap() }
+    }
+
+    // environment of the quote callback, receiving two quotes and the pool
+    fn quote_env(timestamp: u64) {
+        testing_env!(
+            context("dca.near".parse().unwrap(), 0).block_timestamp(timestamp).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            (0..3).map(|_| PromiseResult::Successful(vec![])).collect(),
+        );
+    }
 
-    
+    // withdraw callback of a batch whose output left the pool deposit
+    fn withdraw_confirmed(contract: &mut Contract, batch_id: BatchId) -> HashMap<PositionId, u128> {
+        let amount_out = contract.get_batch(batch_id).unwrap().amount_out;
+        contract.pool_withdraw_callback(batch_id, amount_out, Ok(U128(0)))
+    }
+
+    fn refunded(result: PromiseOrValue<U128>) -> u128 {
+        match result {
+            PromiseOrValue::Value(amount) => amount.0,
+            PromiseOrValue::Promise(_) => panic!("Expected a value"),
+        }
+    }
+
+    fn setup() -> Contract {
+        testing_env!(context(accounts(0), 0).build());
+        Contract::init(
+            "token.near".parse().unwrap(),
+            accounts(0),
+            10,
+            "wrap.near".parse().unwrap(),
+            1,
+            "ref.near".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn create_and_topup() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).build());
+        contract.create_position(None, U128(ONE_NEAR / 10), 60, None, None);
+        assert_eq!(contract.get_position(0).amount, U128(2 * ONE_NEAR));
+
+        testing_env!(context(accounts(1), ONE_NEAR).build());
+        contract.topup(0);
+        assert_eq!(contract.get_position(0).amount, U128(3 * ONE_NEAR));
+    }
+
+    #[test]
+    fn close_position_cleans_up() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).build());
+        contract.create_position(None, U128(ONE_NEAR / 10), 60, None, None);
+
+        testing_env!(context(accounts(1), 1).build());
+        contract.close_position(0);
+        assert!(contract.get_positions(accounts(1)).is_empty());
+        assert!(contract.positions.is_empty());
+    }
+
+    #[test]
+    fn due_index_follows_position_changes() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        assert!(contract.can_swap(None, None));
+        assert!(!contract.can_swap(None, Some(true)));
+
+        contract.pause(0);
+        assert!(!contract.can_swap(None, None));
+        contract.resume(0);
+        assert!(contract.can_swap(None, None));
+
+        contract.change_swap_interval(0, 1_000);
+        assert!(!contract.can_swap(None, None));
+        contract.change_swap_interval(0, 50);
+        assert!(contract.can_swap(None, None));
+
+        // a balance that no longer covers a swap takes the position out of the index
+        testing_env!(context(accounts(1), 1).block_timestamp(100).build());
+        contract.withdraw_near(0, U128(ONE_NEAR + 1));
+        assert!(!contract.can_swap(None, None));
+        testing_env!(context(accounts(1), ONE_NEAR).block_timestamp(100).build());
+        contract.topup(0);
+        assert!(contract.can_swap(None, None));
+
+        testing_env!(context(accounts(1), 1).block_timestamp(100).build());
+        contract.close_position(0);
+        assert!(!contract.can_swap(None, None));
+        assert!(contract.due.is_empty());
+    }
+
+    #[test]
+    fn due_positions_are_taken_in_due_order() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 90, None, None);
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 30, None, None);
+        testing_env!(context(accounts(3), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 500, None, None);
+
+        assert_eq!(contract.due_positions(0, false, 10), vec![1, 0]);
+        assert_eq!(contract.due_positions(0, false, 1), vec![1]);
+    }
+
+    #[test]
+    fn pending_swaps_are_counted_up_to_a_bound() {
+        let mut contract = setup();
+        for position_id in 0..(schedule::MAX_COUNTED_DUE_POSITIONS as u64 + 5) {
+            contract.due.insert((DEFAULT_PAIR_ID, true, 0, position_id), ());
+        }
+        testing_env!(context(accounts(1), 0).block_timestamp(100).build());
+        let pending = contract.get_pending_swaps(None);
+        assert_eq!((pending.forward, pending.reverse), (0, schedule::MAX_COUNTED_DUE_POSITIONS as u32));
+    }
+
+    #[test]
+    fn positions_left_out_of_a_full_batch_go_first_next_time() {
+        let mut contract = setup();
+        contract.batch_swap_threshold = 2;
+
+        for i in 1..4 {
+            testing_env!(context(accounts(i), 2 * ONE_NEAR).block_timestamp(100).build());
+            contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        }
+        assert_eq!(contract.get_pending_swaps(None).forward, 3);
+        assert_eq!(contract.get_pending_swaps(None).reverse, 0);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        assert_eq!(contract.get_batch(0).unwrap().positions, vec![0, 1]);
+
+        callback_env(200);
+        contract.pool_swap_callback(0, Ok(U128(1_000)));
+        callback_env(200);
+        withdraw_confirmed(&mut contract, 0);
+
+        // the position that did not fit is now first, ahead of the ones just served
+        assert_eq!(contract.get_pending_swaps(None).forward, 1);
+        assert_eq!(contract.due_positions(0, false, 2), vec![2]);
+
+        testing_env!(context("dca.near".parse().unwrap(), 0).block_timestamp(250).build());
+        assert_eq!(contract.due_positions(0, false, 3), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn stuck_batches_can_be_failed() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        assert!(contract.get_position(0).in_flight.is_some());
+
+        // the batch never gets past its swap on a venue keeping deposits
+        testing_env!(context(accounts(0), 1).block_timestamp(99 + MIN_STUCK_BATCH_AGE).build());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.fail_stuck_batch(0)));
+        assert!(result.is_err());
+
+        testing_env!(context(accounts(0), 1).block_timestamp(100 + MIN_STUCK_BATCH_AGE).build());
+        contract.fail_stuck_batch(0);
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.status, BatchStatus::Failed);
+        assert_eq!(batch.finished_timestamp, Some(100 + MIN_STUCK_BATCH_AGE));
+        let position = contract.get_position(0);
+        assert!(position.in_flight.is_none());
+        assert_eq!(position.amount, U128(2 * ONE_NEAR));
+        assert_eq!(contract.get_pending_swaps(None).forward, 1);
+        assert!(contract.can_swap(None, None));
+
+        // a swap resolving after that leaves the batch failed
+        callback_env(200 + MIN_STUCK_BATCH_AGE);
+        contract.pool_swap_callback(0, Ok(U128(500)));
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Failed);
+        assert!(get_created_receipts().is_empty());
+    }
+
+    #[test]
+    fn stuck_swapped_batches_are_settled_with_the_recovered_output() {
+        let mut contract = setup();
+        let dcl: AccountId = "dcl.near".parse().unwrap();
+        let route = vec![Hop { pool_id: 2000, token_in: "wrap.near".parse().unwrap(), token_out: "token.near".parse().unwrap() }];
+        contract.set_pair_venue(0, Venue::RefDcl { contract_id: dcl.clone() }, route);
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let amount_in = contract.get_batch(0).unwrap().amount_in.0;
+
+        // the input was swapped but the output never comes back
+        callback_env(110);
+        contract.pool_transfer_callback(0, Ok(amount_in.to_string()));
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Swapped);
+
+        // its input left the contract, the batch cannot be failed
+        testing_env!(context(accounts(0), 1).block_timestamp(100 + MIN_STUCK_BATCH_AGE).build());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.fail_stuck_batch(0)));
+        assert!(result.is_err());
+        testing_env!(context(accounts(0), 1).block_timestamp(99 + MIN_STUCK_BATCH_AGE).build());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.settle_stuck_batch(0, U128(400))));
+        assert!(result.is_err());
+
+        // the owner recovered part of the output, the position is charged and credited it
+        testing_env!(context(accounts(0), 1).block_timestamp(100 + MIN_STUCK_BATCH_AGE).build());
+        assert_eq!(contract.settle_stuck_batch(0, U128(400)), HashMap::from([(0, 400)]));
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.status, BatchStatus::Settled);
+        assert_eq!(batch.amount_out, U128(400));
+        let position = contract.get_position(0);
+        assert!(position.in_flight.is_none());
+        assert_eq!(position.amount, U128(ONE_NEAR));
+        assert_eq!(position.total_swapped, U128(400));
+
+        // output arriving late is kept and carried over to the next batch
+        let output_message = near_sdk::serde_json::to_string(&receiver::TransferMessage::BatchOutput { batch_id: 0 }).unwrap();
+        let mut builder = context(accounts(2), 0);
+        builder.predecessor_account_id("token.near".parse().unwrap()).block_timestamp(200 + MIN_STUCK_BATCH_AGE);
+        testing_env!(builder.build());
+        assert_eq!(refunded(contract.ft_on_transfer(dcl, U128(500), output_message)), 0);
+        assert_eq!(contract.get_dust(), vec![("token.near".parse().unwrap(), U128(500))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Batch is not pending")]
+    fn settled_batches_are_not_stuck() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        callback_env(110);
+        withdraw_confirmed(&mut contract, 0);
+
+        testing_env!(context(accounts(0), 1).block_timestamp(100 + MIN_STUCK_BATCH_AGE).build());
+        contract.fail_stuck_batch(0);
+    }
+
+    #[test]
+    fn failed_transfer_leaves_positions_untouched() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        // the pool rejected the deposit, everything was refunded
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        callback_env(110);
+        contract.pool_transfer_callback(0, Ok("0".to_string()));
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Failed);
+
+        // the pool kept only part of the deposit
+        testing_env!(context(accounts(0), 0).block_timestamp(120).build());
+        contract.swap(None, None);
+        let amount_in = contract.get_batch(1).unwrap().amount_in.0;
+        callback_env(130);
+        contract.pool_transfer_callback(1, Ok((amount_in / 2).to_string()));
+        assert_eq!(contract.get_batch(1).unwrap().status, BatchStatus::Failed);
+
+        for i in 1..3 {
+            let position = contract.get_position(i as u64 - 1);
+            assert_eq!(position.amount, U128(2 * ONE_NEAR));
+            assert_eq!(position.total_swapped, U128(0));
+            assert_eq!(position.last_swap_timestamp, 0);
+        }
+        assert_eq!(contract.get_pending_swaps(None).forward, 2);
+    }
+
+    #[test]
+    fn positions_in_flight_are_reserved_until_the_batch_ends() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        assert_eq!(contract.get_position(0).in_flight, Some(0));
+        // the positions are not batched again
+        assert!(!contract.can_swap(None, None));
+
+        // only what is not being swapped can be withdrawn
+        testing_env!(context(accounts(1), 1).block_timestamp(105).build());
+        contract.withdraw_near(0, U128(ONE_NEAR));
+        assert_eq!(contract.get_position(0).amount, U128(ONE_NEAR));
+
+        // a failed batch releases its positions
+        callback_env(110);
+        contract.pool_transfer_callback(0, Ok("0".to_string()));
+        assert_eq!(contract.get_position(0).in_flight, None);
+        assert_eq!(contract.get_pending_swaps(None).forward, 2);
+
+        // and so does a settled one
+        testing_env!(context(accounts(0), 0).block_timestamp(120).build());
+        contract.swap(None, None);
+        callback_env(130);
+        withdraw_confirmed(&mut contract, 1);
+        assert_eq!(contract.get_position(0).in_flight, None);
+        assert_eq!(contract.get_position(1).in_flight, None);
+        assert_eq!(contract.get_position(1).last_swap_timestamp, 130);
+    }
+
+    #[test]
+    #[should_panic(expected = "Position does not have enough balance")]
+    fn the_swap_in_flight_cannot_be_withdrawn() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+
+        testing_env!(context(accounts(1), 1).block_timestamp(105).build());
+        contract.withdraw_near(0, U128(ONE_NEAR + 1));
+    }
+
+    #[test]
+    fn positions_are_credited_only_after_withdraw() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        // failed swap: the batch fails and nobody is charged
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let amount_in = contract.get_batch(0).unwrap().amount_in.0;
+        callback_env(110);
+        contract.pool_transfer_callback(0, Ok(amount_in.to_string()));
+        callback_env(115);
+        contract.pool_swap_callback(0, Err(PromiseError::Failed));
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Failed);
+        assert_eq!(contract.get_position(0).amount, U128(2 * ONE_NEAR));
+        // the input is refunded before the pool is used again
+        assert!(!contract.can_swap(None, None));
+        callback_env(117);
+        contract.pool_refund_callback(0, "wrap.near".parse().unwrap(), U128(amount_in), Ok(()));
+        assert!(contract.can_swap(None, None));
+
+        // swap goes through but the withdraw fails: the batch waits for a retry
+        testing_env!(context(accounts(0), 0).block_timestamp(120).build());
+        contract.swap(None, None);
+        callback_env(130);
+        contract.pool_transfer_callback(1, Ok(amount_in.to_string()));
+        callback_env(135);
+        contract.pool_swap_callback(1, Ok(U128(500)));
+        callback_env(138);
+        assert!(contract.pool_withdraw_callback(1, U128(500), Err(PromiseError::Failed)).is_empty());
+        let event = &emitted_events()[0];
+        assert_eq!(event["event"], "batch_withdraw_failed");
+        assert_eq!(event["data"], near_sdk::serde_json::json!({"batch_id": 1, "token_id": "token.near", "amount": "500"}));
+        let batch = contract.get_batch(1).unwrap();
+        assert_eq!(batch.status, BatchStatus::Swapped);
+        assert_eq!(batch.amount_out, U128(500));
+        assert_eq!(contract.get_position(0).total_swapped, U128(0));
+
+        // Ref resolves a withdraw whose transfer failed, but the output is credited
+        // back to the deposit: nothing is settled
+        testing_env!(context(accounts(0), 1).block_timestamp(140).build());
+        contract.retry_batch_withdraw(1);
+        callback_env(142);
+        contract.pool_pre_withdraw_callback(1, Ok(U128(800)));
+        let args = get_created_receipts().into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                near_sdk::mock::MockAction::FunctionCallWeight { method_name, args, .. } if method_name == b"withdraw" => Some(args),
+                _ => None,
+            })
+            .unwrap();
+        let args: near_sdk::serde_json::Value = near_sdk::serde_json::from_slice(&args).unwrap();
+        assert_eq!(args, near_sdk::serde_json::json!({"token_id": "token.near", "amount": "500", "skip_unwrap_near": true}));
+        callback_env(145);
+        assert!(contract.pool_withdraw_callback(1, U128(800), Ok(U128(800))).is_empty());
+        assert_eq!(contract.get_batch(1).unwrap().status, BatchStatus::Swapped);
+        assert_eq!(contract.get_position(0).total_swapped, U128(0));
+
+        testing_env!(context(accounts(0), 1).block_timestamp(140).build());
+        contract.retry_batch_withdraw(1);
+        callback_env(150);
+        withdraw_confirmed(&mut contract, 1);
+        assert_eq!(contract.get_batch(1).unwrap().status, BatchStatus::Settled);
+        let position = contract.get_position(0);
+        assert_eq!(position.amount, U128(ONE_NEAR));
+        assert_eq!(position.last_swap_timestamp, 150);
+        assert!(position.total_swapped.0 > 0);
+    }
+
+    #[test]
+    fn pool_deposits_are_used_by_one_batch_at_a_time() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let amount_in = contract.get_batch(0).unwrap().amount_in.0;
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(105).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        callback_env(110);
+        contract.pool_transfer_callback(0, Ok(amount_in.to_string()));
+        callback_env(115);
+        contract.pool_swap_callback(0, Ok(U128(500)));
+        callback_env(118);
+        contract.pool_withdraw_callback(0, U128(500), Err(PromiseError::Failed));
+
+        // a new batch would move the same deposits while the output is withdrawn
+        assert_eq!(contract.get_pending_swaps(None).forward, 1);
+        assert!(!contract.can_swap(None, None));
+        testing_env!(context(accounts(0), 1).block_timestamp(120).build());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.recover_pool_balance(ref_venue(), "token.near".parse().unwrap(), U128(500))));
+        assert!(result.is_err());
+
+        contract.retry_batch_withdraw(0);
+        callback_env(130);
+        withdraw_confirmed(&mut contract, 0);
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Settled);
+        assert!(contract.can_swap(None, None));
+        testing_env!(context(accounts(0), 0).block_timestamp(140).build());
+        contract.swap(None, None);
+        assert_eq!(contract.get_batch(1).unwrap().positions, vec![1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Another batch is using the pool deposits of the pair")]
+    fn pairs_sharing_a_pool_deposit_are_not_swapped_together() {
+        let mut contract = setup();
+        contract.add_pair("wrap.near".parse().unwrap(), "usdc.near".parse().unwrap(), ref_venue(), 7);
+
+        testing_env!(context(accounts(1), 4 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        contract.create_position(Some(1), U128(ONE_NEAR), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        contract.swap(Some(1), None);
+    }
+
+    #[test]
+    fn quote_leaves_out_positions_with_a_tighter_slippage() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, Some(50));
+        testing_env!(context(accounts(2), 4 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(2 * ONE_NEAR), 50, None, Some(300));
+        testing_env!(context(accounts(3), 4 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, Some(200));
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let batch = contract.get_batch(0).unwrap();
+        let reference_amount = contract.amount_after_fees(ONE_NEAR);
+
+        // the whole batch trades 1% below the reference price
+        quote_env(110);
+        let full_quote = batch.amount_in.0 * 99 / 100;
+        contract.pool_quote_callback(0, 0, U128(reference_amount), Ok(VenueQuote::Amount(U128(full_quote))), Ok(VenueQuote::Amount(U128(reference_amount))), Ok(VenuePool::Ref(pool())));
+
+        let full_amount_in = batch.amount_in.0;
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.quoted_amount_out, U128(mul_div(full_quote, batch.amount_in.0, full_amount_in)));
+        assert_eq!(batch.positions, vec![1, 2]);
+        assert_eq!(batch.amount, U128(3 * ONE_NEAR));
+        assert_eq!(batch.amount_in, U128(contract.amount_after_fees(3 * ONE_NEAR)));
+        // 2% below the reference price for the remaining positions
+        assert_eq!(batch.min_amount_out, U128(batch.amount_in.0 * 98 / 100));
+
+        // the position left out is due again, the others are in flight. It is batched
+        // once the batch in flight is done with the pool.
+        assert_eq!(contract.get_pending_swaps(None).forward, 1);
+        assert!(!contract.can_swap(None, None));
+        callback_env(112);
+        contract.pool_transfer_callback(0, Ok(batch.amount_in.0.to_string()));
+        callback_env(114);
+        contract.pool_swap_callback(0, Ok(batch.quoted_amount_out));
+        callback_env(116);
+        withdraw_confirmed(&mut contract, 0);
+
+        // a price impact nobody accepts fails the batch
+        testing_env!(context(accounts(0), 0).block_timestamp(120).build());
+        contract.swap(None, None);
+        let batch = contract.get_batch(1).unwrap();
+        assert_eq!(batch.positions, vec![0]);
+        quote_env(130);
+        contract.pool_quote_callback(1, 0, U128(reference_amount), Ok(VenueQuote::Amount(U128(batch.amount_in.0 / 2))), Ok(VenueQuote::Amount(U128(reference_amount))), Ok(VenuePool::Ref(pool())));
+        assert_eq!(contract.get_batch(1).unwrap().status, BatchStatus::Failed);
+        assert_eq!(contract.get_pending_swaps(None).forward, 1);
+    }
+
+    #[test]
+    fn quote_from_an_unrelated_pool_fails_the_batch() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let amount_in = contract.get_batch(0).unwrap().amount_in;
+
+        let mut other_pool = pool();
+        other_pool.token_account_ids[1] = "other.near".parse().unwrap();
+        quote_env(110);
+        contract.pool_quote_callback(0, 0, amount_in, Ok(VenueQuote::Amount(amount_in)), Ok(VenueQuote::Amount(amount_in)), Ok(VenuePool::Ref(other_pool)));
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Failed);
+    }
+
+    #[test]
+    fn routes_are_quoted_and_swapped_hop_by_hop() {
+        let mut contract = setup();
+        let usdc: AccountId = "usdc.near".parse().unwrap();
+        let route = vec![
+            Hop { pool_id: 2, token_in: "wrap.near".parse().unwrap(), token_out: usdc.clone() },
+            Hop { pool_id: 3, token_in: usdc.clone(), token_out: "token.near".parse().unwrap() },
+        ];
+        contract.set_pair_route(0, route.clone());
+        assert_eq!(contract.get_pair(0).route(true)[0], Hop { pool_id: 3, token_in: "token.near".parse().unwrap(), token_out: usdc.clone() });
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.route, route);
+        let amount_in = batch.amount_in.0;
+
+        // the first hop is quoted on the wNEAR/USDC pool and the second one is quoted
+        // with its output
+        let mut first_pool = pool();
+        first_pool.token_account_ids[1] = usdc.clone();
+        quote_env(110);
+        contract.pool_quote_callback(0, 0, U128(amount_in), Ok(VenueQuote::Amount(U128(amount_in * 2))), Ok(VenueQuote::Amount(U128(amount_in * 2))), Ok(VenuePool::Ref(first_pool)));
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Pending);
+        assert_eq!(contract.get_batch(0).unwrap().min_amount_out, U128(0));
+
+        // the pool of the last hop must trade USDC
+        quote_env(120);
+        contract.pool_quote_callback(0, 1, U128(amount_in), Ok(VenueQuote::Amount(U128(amount_in))), Ok(VenueQuote::Amount(U128(amount_in))), Ok(VenuePool::Ref(pool())));
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Failed);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(130).build());
+        contract.swap(None, None);
+        let mut last_pool = pool();
+        last_pool.token_account_ids[0] = usdc;
+        quote_env(140);
+        contract.pool_quote_callback(1, 1, U128(amount_in), Ok(VenueQuote::Amount(U128(amount_in))), Ok(VenueQuote::Amount(U128(amount_in))), Ok(VenuePool::Ref(last_pool)));
+        // 1% below the quote of the route
+        assert_eq!(contract.get_batch(1).unwrap().min_amount_out, U128(amount_in * 99 / 100));
+
+        // only the first action sets its input and only the last one its least output
+        let actions = near_sdk::serde_json::to_value(create_ref_message(&route, 1_000, 900)).unwrap();
+        assert_eq!(actions[0]["amount_in"], "1000");
+        assert_eq!(actions[0]["min_amount_out"], "0");
+        assert!(actions[1].get("amount_in").is_none());
+        assert_eq!(actions[1]["min_amount_out"], "900");
+    }
+
+    #[test]
+    #[should_panic(expected = "Route does not connect at usdc.near")]
+    fn routes_must_connect_the_tokens_of_the_pair() {
+        let mut contract = setup();
+
+        contract.set_pair_route(0, vec![
+            Hop { pool_id: 2, token_in: "wrap.near".parse().unwrap(), token_out: "usdc.near".parse().unwrap() },
+            Hop { pool_id: 3, token_in: "usdt.near".parse().unwrap(), token_out: "token.near".parse().unwrap() },
+        ]);
+    }
+
+    #[test]
+    fn pairs_are_quoted_on_their_venue() {
+        let mut contract = setup();
+        let jumbo: AccountId = "jumbo.near".parse().unwrap();
+        let route = vec![Hop { pool_id: 4, token_in: "wrap.near".parse().unwrap(), token_out: "token.near".parse().unwrap() }];
+        contract.set_pair_venue(0, Venue::Jumbo { contract_id: jumbo.clone() }, route);
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        assert!(get_created_receipts().iter().any(|receipt| receipt.receiver_id == jumbo));
+        assert!(get_created_receipts().iter().all(|receipt| receipt.receiver_id != "ref.near".parse::<AccountId>().unwrap()));
+
+        // batches already created stay on the venue they were quoted on
+        contract.set_pair_venue(0, ref_venue(), contract.get_pair(0).route);
+        assert_eq!(contract.get_batch(0).unwrap().venue, Venue::Jumbo { contract_id: jumbo });
+    }
+
+    #[test]
+    fn dcl_batches_swap_on_transfer_and_settle_on_the_output() {
+        let mut contract = setup();
+        let dcl: AccountId = "dcl.near".parse().unwrap();
+        let route = vec![Hop { pool_id: 2000, token_in: "wrap.near".parse().unwrap(), token_out: "token.near".parse().unwrap() }];
+        contract.set_pair_venue(0, Venue::RefDcl { contract_id: dcl.clone() }, route);
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let amount_in = contract.get_batch(0).unwrap().amount_in.0;
+
+        let dcl_pool = DclPoolInfo {
+            token_x: "token.near".parse().unwrap(),
+            token_y: "wrap.near".parse().unwrap(),
+            total_x: U128(1_000 * ONE_NEAR),
+            total_y: U128(1_000 * ONE_NEAR),
+        };
+        quote_env(110);
+        contract.pool_quote_callback(0, 0, U128(amount_in), Ok(VenueQuote::Dcl { amount: U128(amount_in) }), Ok(VenueQuote::Dcl { amount: U128(amount_in) }), Ok(VenuePool::Dcl(dcl_pool)));
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.min_amount_out, U128(amount_in * 99 / 100));
+
+        // the input is swapped as it is transferred, the output comes back with the echo
+        let message = batch.venue.deposit_message(&batch.route, batch.amount_in.0, batch.min_amount_out.0, "echo".to_string());
+        let message: near_sdk::serde_json::Value = near_sdk::serde_json::from_str(&message).unwrap();
+        assert_eq!(message["Swap"]["pool_ids"][0], "token.near|wrap.near|2000");
+        assert_eq!(message["Swap"]["output_token"], "token.near");
+        assert_eq!(message["Swap"]["client_echo"], "echo");
+
+        callback_env(120);
+        contract.pool_transfer_callback(0, Ok(amount_in.to_string()));
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Swapped);
+        assert_eq!(contract.get_position(0).total_swapped, U128(0));
+
+        let output_message = near_sdk::serde_json::to_string(&receiver::TransferMessage::BatchOutput { batch_id: 0 }).unwrap();
+        let mut builder = context(accounts(2), 0);
+        builder.predecessor_account_id("token.near".parse().unwrap()).block_timestamp(130);
+
+        // only the venue of the batch can send its output
+        testing_env!(builder.build());
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(2), U128(500), output_message.clone())), 500);
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Swapped);
+
+        testing_env!(builder.build());
+        assert_eq!(refunded(contract.ft_on_transfer(dcl, U128(500), output_message)), 0);
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Settled);
+        assert_eq!(contract.get_position(0).total_swapped, U128(500));
+        assert_eq!(contract.get_position(0).amount, U128(ONE_NEAR));
+    }
+
+    #[test]
+    fn instant_swaps_send_the_actions_and_read_the_output() {
+        let mut contract = setup();
+        let route = contract.get_pair(0).route;
+        contract.set_pair_venue(0, Venue::RefV2Instant { contract_id: "ref.near".parse().unwrap() }, route);
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let amount_in = contract.get_batch(0).unwrap().amount_in.0;
+        quote_env(110);
+        contract.pool_quote_callback(0, 0, U128(amount_in), Ok(VenueQuote::Amount(U128(amount_in))), Ok(VenueQuote::Amount(U128(amount_in))), Ok(VenuePool::Ref(pool())));
+
+        // a single transfer to Ref carries the actions
+        let args = get_created_receipts().into_iter()
+            .filter(|receipt| receipt.receiver_id == "wrap.near".parse::<AccountId>().unwrap())
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                near_sdk::mock::MockAction::FunctionCallWeight { method_name, args, .. } if method_name == b"ft_transfer_call" => Some(args),
+                _ => None,
+            })
+            .unwrap();
+        let args: near_sdk::serde_json::Value = near_sdk::serde_json::from_slice(&args).unwrap();
+        assert_eq!(args["receiver_id"], "ref.near");
+        let message: near_sdk::serde_json::Value = near_sdk::serde_json::from_str(args["msg"].as_str().unwrap()).unwrap();
+        assert_eq!(message["actions"][0]["amount_in"], amount_in.to_string());
+        assert_eq!(message["actions"][0]["min_amount_out"], (amount_in * 99 / 100).to_string());
+
+        // the output comes back before the transfer resolves, its callback credits the
+        // amount received with the settlement gas of the batch
+        let mut builder = context(accounts(2), 0);
+        builder.predecessor_account_id("token.near".parse().unwrap()).block_timestamp(120);
+        testing_env!(builder.build());
+        let echo = message["client_echo"].as_str().unwrap().to_string();
+        assert_eq!(refunded(contract.ft_on_transfer("ref.near".parse().unwrap(), U128(amount_in - 7), echo.clone())), 0);
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Swapped);
+        assert_eq!(contract.get_position(0).total_swapped, U128(0));
+        testing_env!(builder.build());
+        assert_eq!(refunded(contract.ft_on_transfer("ref.near".parse().unwrap(), U128(amount_in - 7), echo)), amount_in - 7);
+
+        let batch = contract.get_batch(0).unwrap();
+        let mut builder = context("dca.near".parse().unwrap(), 0);
+        builder.block_timestamp(130).prepaid_gas(GAS_FOR_SWAP_TRANSFER_CALLBACK.saturating_add(batch.settlement_gas));
+        testing_env!(builder.build(), near_sdk::test_vm_config(), near_sdk::RuntimeFeesConfig::test(), Default::default(), vec![PromiseResult::Successful(vec![])]);
+        contract.pool_transfer_callback(0, Ok(amount_in.to_string()));
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.status, BatchStatus::Settled);
+        assert_eq!(batch.amount_out, U128(amount_in - 7));
+        assert_eq!(contract.get_position(0).total_swapped, U128(amount_in - 7));
+    }
+
+    #[test]
+    fn output_is_split_exactly_and_dust_carried_over() {
+        let mut contract = setup();
+
+        // a position holding less than 1% of the batch
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 200), 50, None, None);
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 2), 50, None, None);
+        testing_env!(context(accounts(3), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 2), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        contract.batches.get_mut(&0).unwrap().amount_out = U128(1_000);
+        callback_env(110);
+        withdraw_confirmed(&mut contract, 0);
+
+        // 1000 * 0.005 / 1.005 = 4.97, 1000 * 0.5 / 1.005 = 497.5
+        assert_eq!(contract.get_position(0).total_swapped, U128(4));
+        assert_eq!(contract.get_position(1).total_swapped, U128(497));
+        assert_eq!(contract.get_position(2).total_swapped, U128(497));
+        assert_eq!(contract.get_dust(), vec![("token.near".parse().unwrap(), U128(2))]);
+
+        // the dust is part of the next batch of the same token
+        testing_env!(context(accounts(0), 0).block_timestamp(200).build());
+        contract.swap(None, None);
+        contract.batches.get_mut(&1).unwrap().amount_out = U128(1_000);
+        callback_env(210);
+        withdraw_confirmed(&mut contract, 1);
+
+        // 1002 * 0.005 / 1.005 = 4.98, 1002 * 0.5 / 1.005 = 498.5
+        assert_eq!(contract.get_position(0).total_swapped, U128(8));
+        assert_eq!(contract.get_position(1).total_swapped, U128(995));
+        assert_eq!(contract.get_position(2).total_swapped, U128(995));
+        assert_eq!(contract.get_dust(), vec![("token.near".parse().unwrap(), U128(2))]);
+    }
+
+    #[test]
+    fn batches_are_sized_by_the_attached_gas() {
+        let mut contract = setup();
+        contract.set_batch_swap_threshold(50);
+
+        for i in 1..5 {
+            testing_env!(context(accounts(i), 2 * ONE_NEAR).block_timestamp(100).build());
+            contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        }
+
+        // the gas of the pipeline and of locking and crediting three positions and a half
+        let pipeline_gas = GAS_FOR_SWAP.saturating_add(GAS_FOR_QUOTE_CALLBACK).saturating_add(GAS_FOR_SETTLE_RESERVE);
+        let position_gas = DEFAULT_GAS_PER_POSITION.saturating_add(GAS_FOR_BATCH_POSITION);
+        let mut builder = context(accounts(0), 0);
+        builder.block_timestamp(100).prepaid_gas(pipeline_gas.saturating_add(position_gas.saturating_mul(7).saturating_div(2)));
+        testing_env!(builder.build());
+        contract.swap(None, None);
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.positions, vec![0, 1, 2]);
+        assert_eq!(batch.settlement_gas, GAS_FOR_SETTLE_RESERVE.saturating_add(DEFAULT_GAS_PER_POSITION.saturating_mul(3)));
+    }
+
+    #[test]
+    fn full_batches_fit_in_the_gas_of_a_transaction() {
+        let mut contract = setup();
+        contract.set_batch_swap_threshold(50);
+
+        for i in 0..20 {
+            testing_env!(context(accounts(i % 5 + 1), 2 * ONE_NEAR).block_timestamp(100).build());
+            contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        }
+
+        // more positions are due than fit, the batch takes what the gas pays for
+        let mut builder = context(accounts(0), 0);
+        builder.block_timestamp(100).prepaid_gas(MAX_TRANSACTION_GAS);
+        testing_env!(builder.build());
+        contract.swap(None, None);
+        let batch_positions = contract.get_batch(0).unwrap().positions.len();
+        assert!(batch_positions > 1 && batch_positions < 20);
+        assert_eq!(contract.get_pending_swaps(None).forward as usize, 20 - batch_positions);
+        // the state is written when the call ends, within the gas attached
+        drop(contract);
+        assert!(env::used_gas() <= MAX_TRANSACTION_GAS);
+    }
+
+    #[test]
+    fn longest_routes_fit_a_position_in_a_transaction() {
+        let mut contract = setup();
+        let route: Vec<Hop> = (0..pair::MAX_ROUTE_HOPS).map(|hop| Hop {
+            pool_id: hop as u64,
+            token_in: if hop == 0 { "wrap.near".parse().unwrap() } else { format!("mid{}.near", hop).parse().unwrap() },
+            token_out: if hop + 1 == pair::MAX_ROUTE_HOPS { "token.near".parse().unwrap() } else { format!("mid{}.near", hop + 1).parse().unwrap() },
+        }).collect();
+        contract.set_pair_route(0, route);
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        let mut builder = context(accounts(0), 0);
+        builder.block_timestamp(100).prepaid_gas(MAX_TRANSACTION_GAS);
+        testing_env!(builder.build());
+        contract.swap(None, None);
+        assert_eq!(contract.get_batch(0).unwrap().positions.len(), 1);
+    }
+
+    #[test]
+    fn batches_settle_with_the_gas_they_carry() {
+        let mut contract = setup();
+        contract.set_batch_swap_threshold(50);
+
+        for i in 1..6 {
+            testing_env!(context(accounts(i), 2 * ONE_NEAR).block_timestamp(100).build());
+            contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        }
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.positions.len(), 5);
+        contract.batches.get_mut(&0).unwrap().status = BatchStatus::Swapped;
+        contract.batches.get_mut(&0).unwrap().amount_out = U128(1_000);
+
+        // the withdraw callback gets exactly the gas the pre-withdraw callback attaches
+        let mut builder = context("dca.near".parse().unwrap(), 0);
+        builder.block_timestamp(110).prepaid_gas(GAS_FOR_WITHDRAW_CALLBACK.saturating_add(batch.settlement_gas));
+        testing_env!(builder.build(), near_sdk::test_vm_config(), near_sdk::RuntimeFeesConfig::test(), Default::default(), vec![PromiseResult::Successful(vec![])]);
+        assert_eq!(withdraw_confirmed(&mut contract, 0).len(), 5);
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.status, BatchStatus::Settled);
+        assert_eq!(batch.settled_positions, 5);
+    }
+
+    #[test]
+    fn settlements_continue_when_the_gas_runs_out() {
+        let mut contract = setup();
+
+        for i in 1..4 {
+            testing_env!(context(accounts(i), 2 * ONE_NEAR).block_timestamp(100).build());
+            contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        }
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        contract.batches.get_mut(&0).unwrap().status = BatchStatus::Swapped;
+        contract.batches.get_mut(&0).unwrap().amount_out = U128(1_000);
+
+        // too little gas to credit any position, nor to hand the settlement over to a
+        // step that could
+        let mut builder = context("dca.near".parse().unwrap(), 0);
+        builder.block_timestamp(110).prepaid_gas(GAS_FOR_SETTLE_RESERVE.saturating_add(DEFAULT_GAS_PER_POSITION).saturating_sub(Gas::from_gas(1)));
+        testing_env!(builder.build(), near_sdk::test_vm_config(), near_sdk::RuntimeFeesConfig::test(), Default::default(), vec![PromiseResult::Successful(vec![])]);
+        assert!(withdraw_confirmed(&mut contract, 0).is_empty());
+        assert_eq!(contract.get_batch(0).unwrap().status, BatchStatus::Settling);
+        assert_eq!(contract.get_position(0).total_swapped, U128(0));
+        assert!(get_created_receipts().is_empty());
+
+        // anyone finishes it with settle_batch
+        testing_env!(context(accounts(3), 0).block_timestamp(120).build());
+        assert_eq!(contract.settle_batch(0).len(), 3);
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.status, BatchStatus::Settled);
+        assert_eq!(batch.settled_positions, 3);
+        assert_eq!(contract.get_position(2).total_swapped, U128(333));
+        assert_eq!(contract.get_dust(), vec![("token.near".parse().unwrap(), U128(1))]);
+    }
+
+    #[test]
+    fn fees_accrue_on_settlement_and_can_be_claimed() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 2), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let batch = contract.get_batch(0).unwrap();
+        // 10 basis points of each swap
+        assert_eq!(batch.fee, U128(ONE_NEAR / 1_000 + ONE_NEAR / 2_000));
+        assert_eq!(batch.amount_in.0 + batch.fee.0, batch.amount.0);
+
+        // nothing is accrued before the batch settles
+        assert!(contract.get_accrued_fees().is_empty());
+        contract.batches.get_mut(&0).unwrap().amount_out = U128(1_000);
+        callback_env(110);
+        withdraw_confirmed(&mut contract, 0);
+        assert_eq!(contract.get_accrued_fees(), vec![("wrap.near".parse().unwrap(), batch.fee)]);
+
+        // a failed claim puts the fees back
+        testing_env!(context(accounts(0), 1).block_timestamp(120).build());
+        contract.claim_fees("wrap.near".parse().unwrap(), accounts(0));
+        assert!(contract.get_accrued_fees().is_empty());
+        callback_env(130);
+        contract.claim_fees_callback("wrap.near".parse().unwrap(), batch.fee, Err(PromiseError::Failed));
+        assert_eq!(contract.get_accrued_fees(), vec![("wrap.near".parse().unwrap(), batch.fee)]);
+    }
+
+    #[test]
+    fn batches_are_recorded_and_listed() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 2), 50, None, None);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        callback_env(110);
+        contract.pool_swap_callback(0, Ok(U128(1_000)));
+        callback_env(120);
+        withdraw_confirmed(&mut contract, 0);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(200).build());
+        contract.swap(None, None);
+        callback_env(210);
+        contract.pool_transfer_callback(1, Err(PromiseError::Failed));
+
+        assert_eq!(contract.get_batch_count(), 2);
+        let batches = contract.list_batches(None, None);
+        assert_eq!(batches.iter().map(|batch| batch.id).collect::<Vec<_>>(), vec![0, 1]);
+
+        let settled = &batches[0];
+        assert_eq!(settled.status, BatchStatus::Settled);
+        assert_eq!(settled.accounts, vec![accounts(1), accounts(2)]);
+        assert_eq!(settled.keeper, accounts(0));
+        assert_eq!(settled.amount_out, U128(1_000));
+        assert_eq!((settled.timestamp, settled.swapped_timestamp, settled.finished_timestamp), (100, Some(110), Some(120)));
+
+        let failed = &batches[1];
+        assert_eq!(failed.status, BatchStatus::Failed);
+        assert_eq!((failed.timestamp, failed.swapped_timestamp, failed.finished_timestamp), (200, None, Some(210)));
+
+        // pages stop at the last batch
+        assert_eq!(contract.list_batches(Some(1), Some(5)).len(), 1);
+        assert!(contract.list_batches(Some(2), Some(5)).is_empty());
+        assert_eq!(contract.list_batches(Some(0), Some(1))[0].id, 0);
+
+        // pages are bounded, with or without a limit
+        for batch_id in 2..200 {
+            contract.batches.insert(batch_id, Batch { id: batch_id, ..batches[1].clone() });
+        }
+        contract.next_batch_id = 200;
+        let page = contract.list_batches(None, None);
+        assert_eq!((page.len(), page[0].id), (DEFAULT_PAGE_LIMIT as usize, 0));
+        let page = contract.list_batches(Some(90), Some(1_000));
+        assert_eq!((page.len(), page[0].id), (MAX_PAGE_LIMIT as usize, 90));
+    }
+
+    #[test]
+    fn user_stats_outlive_withdrawals() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 3 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        for (batch_id, timestamp) in [(0, 100), (1, 200)] {
+            testing_env!(context(accounts(0), 0).block_timestamp(timestamp).build());
+            contract.swap(None, None);
+            callback_env(timestamp + 10);
+            contract.pool_swap_callback(batch_id, Ok(U128(4_000)));
+            withdraw_confirmed(&mut contract, batch_id);
+        }
+
+        // withdrawing what was bought does not change what was swapped
+        testing_env!(context(accounts(1), 1).block_timestamp(300).build());
+        contract.withdraw_ft(0, "token.near".parse().unwrap(), U128(8_000));
+        assert_eq!(contract.get_position(0).total_swapped, U128(0));
+
+        let stats = contract.get_user_stats(accounts(1));
+        assert_eq!(stats.trades.len(), 1);
+        let trade = &stats.trades[0];
+        assert_eq!((trade.pair_id, trade.reverse, trade.swaps), (0, false, 2));
+        assert_eq!(trade.token_out, "token.near".parse::<AccountId>().unwrap());
+        assert_eq!((trade.amount_in, trade.amount_out), (U128(2 * ONE_NEAR), U128(8_000)));
+        assert_eq!(trade.fees, U128(2 * ONE_NEAR / 1_000));
+        assert_eq!(trade.average_price, Some(U128(ONE_NEAR / 4_000 * math::PRICE_PRECISION)));
+        assert_eq!((trade.first_swap_timestamp, trade.last_swap_timestamp), (110, 210));
+        assert_eq!(stats.recent_fills.iter().map(|fill| fill.batch_id).collect::<Vec<_>>(), vec![0, 1]);
+        assert!(contract.get_user_stats(accounts(2)).trades.is_empty());
+
+        // only the latest fills are kept
+        for batch_id in 2..30 {
+            let fill = stats.recent_fills[0].clone();
+            contract.internal_record_fill(&accounts(1), (&"wrap.near".parse().unwrap(), &"token.near".parse().unwrap()), stats::Fill { batch_id, ..fill });
+        }
+        let fills = contract.get_user_stats(accounts(1)).recent_fills;
+        assert_eq!(fills.len(), stats::MAX_RECENT_FILLS);
+        assert_eq!((fills.front().unwrap().batch_id, fills.back().unwrap().batch_id), (10, 29));
+    }
+
+    #[test]
+    fn metrics_follow_positions_and_batches() {
+        let mut contract = setup();
+        let wrap: AccountId = "wrap.near".parse().unwrap();
+        let token: AccountId = "token.near".parse().unwrap();
+
+        testing_env!(context(accounts(1), 3 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 2), 50, None, None);
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        contract.pause(2);
+
+        let metrics = contract.get_metrics();
+        assert_eq!((metrics.accounts, metrics.active_positions, metrics.paused_positions), (2, 2, 1));
+        assert_eq!(metrics.deposits[&wrap], U128(7 * ONE_NEAR));
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        callback_env(110);
+        contract.pool_swap_callback(0, Ok(U128(3_000)));
+        withdraw_confirmed(&mut contract, 0);
+        testing_env!(context(accounts(0), 0).block_timestamp(200).build());
+        contract.swap(None, None);
+        callback_env(210);
+        contract.pool_transfer_callback(1, Err(PromiseError::Failed));
+
+        let metrics = contract.get_metrics();
+        assert_eq!(metrics.deposits[&wrap], U128(7 * ONE_NEAR - 3 * ONE_NEAR / 2));
+        assert_eq!(metrics.deposits[&token], U128(3_000));
+        assert_eq!(metrics.volumes.len(), 1);
+        assert_eq!((metrics.volumes[0].amount_in, metrics.volumes[0].amount_out), (U128(3 * ONE_NEAR / 2), U128(3_000)));
+        assert_eq!(metrics.fees[&wrap], contract.get_batch(0).unwrap().fee);
+        assert_eq!((metrics.batches_settled, metrics.batches_failed, metrics.last_batch_timestamp), (1, 1, 200));
+
+        // closing the last position of an account removes it with its balances
+        testing_env!(context(accounts(2), 1).block_timestamp(300).build());
+        contract.close_position(2);
+        let metrics = contract.get_metrics();
+        assert_eq!((metrics.accounts, metrics.active_positions, metrics.paused_positions), (1, 2, 0));
+        assert_eq!(metrics.deposits[&wrap], U128(5 * ONE_NEAR - 3 * ONE_NEAR / 2));
+    }
+
+    // NEP-297 events logged by the last call
+    fn emitted_events() -> Vec<near_sdk::serde_json::Value> {
+        near_sdk::test_utils::get_logs().iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| near_sdk::serde_json::from_str(event).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn batches_emit_events() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        let events = emitted_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["standard"], "near-dca");
+        assert_eq!(events[0]["version"], "1.0.0");
+        assert_eq!(events[0]["event"], "position_created");
+        assert_eq!(events[0]["data"]["account_id"], accounts(1).to_string());
+        assert_eq!(events[0]["data"]["amount"], (2 * ONE_NEAR).to_string());
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let events = emitted_events();
+        assert_eq!(events[0]["event"], "batch_started");
+        assert_eq!(events[0]["data"]["positions"], near_sdk::serde_json::json!([0]));
+        assert_eq!(events[0]["data"]["keeper"], accounts(0).to_string());
+
+        contract.batches.get_mut(&0).unwrap().amount_out = U128(1_000);
+        callback_env(110);
+        withdraw_confirmed(&mut contract, 0);
+        let events = emitted_events();
+        let names: Vec<_> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["position_swapped", "fee_accrued", "batch_settled"]);
+        assert_eq!(events[0]["data"]["position_id"], 0);
+        assert_eq!(events[0]["data"]["source"], "wrap.near");
+        assert_eq!(events[0]["data"]["target_amount"], "1000");
+        assert_eq!(events[2]["data"]["amount_out"], "1000");
+
+        // a failed batch says why
+        testing_env!(context(accounts(0), 0).block_timestamp(200).build());
+        contract.swap(None, None);
+        callback_env(210);
+        contract.pool_transfer_callback(1, Err(PromiseError::Failed));
+        let events = emitted_events();
+        assert_eq!(events.last().unwrap()["event"], "batch_failed");
+        assert_eq!(events.last().unwrap()["data"]["batch_id"], 1);
+
+        // input left in the pool by a failed refund
+        callback_env(220);
+        contract.pool_refund_callback(1, "wrap.near".parse().unwrap(), U128(300), Err(PromiseError::Failed));
+        let event = &emitted_events()[0];
+        assert_eq!(event["event"], "batch_refund_failed");
+        assert_eq!(event["data"], near_sdk::serde_json::json!({"batch_id": 1, "token_id": "wrap.near", "amount": "300"}));
+
+        testing_env!(context(accounts(0), 0).build());
+        contract.set_fees(20);
+        assert_eq!(emitted_events()[0]["data"], near_sdk::serde_json::json!({"setting": "fees", "value": 20}));
+    }
+
+    #[test]
+    fn anyone_can_swap_and_earn_the_keeper_bounty() {
+        let mut contract = setup();
+        contract.set_keeper_config(KeeperConfig { bounty_bps: 2_000, cooldown: 1_000, ..KeeperConfig::default() });
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+
+        testing_env!(context(accounts(3), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.keeper, accounts(3));
+
+        // the bounty is paid out of the fee once the batch settles
+        assert!(contract.get_keeper_rewards(accounts(3)).is_empty());
+        contract.batches.get_mut(&0).unwrap().amount_out = U128(1_000);
+        callback_env(110);
+        withdraw_confirmed(&mut contract, 0);
+        let bounty = batch.fee.0 / 5;
+        assert_eq!(contract.get_keeper_rewards(accounts(3)), HashMap::from([("wrap.near".parse().unwrap(), U128(bounty))]));
+        assert_eq!(contract.get_accrued_fees(), vec![("wrap.near".parse().unwrap(), U128(batch.fee.0 - bounty))]);
+
+        // a failed claim keeps the rewards
+        testing_env!(context(accounts(3), 1).block_timestamp(120).build());
+        contract.claim_keeper_rewards("wrap.near".parse().unwrap());
+        assert!(contract.get_keeper_rewards(accounts(3)).is_empty());
+        callback_env(130);
+        contract.claim_keeper_rewards_callback(accounts(3), "wrap.near".parse().unwrap(), U128(bounty), Err(PromiseError::Failed));
+        assert_eq!(contract.get_keeper_rewards(accounts(3)).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "A batch of this pair was started too recently")]
+    fn keepers_wait_for_the_cooldown() {
+        let mut contract = setup();
+        contract.set_keeper_config(KeeperConfig { bounty_bps: 2_000, cooldown: 1_000, ..KeeperConfig::default() });
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(3), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+
+        // the batch failed, the position is due again
+        callback_env(110);
+        contract.pool_transfer_callback(0, Ok("0".to_string()));
+        testing_env!(context(accounts(3), 0).block_timestamp(200).build());
+        contract.swap(None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Attach at least")]
+    fn keepers_attach_the_gas_of_the_whole_batch() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        let mut builder = context(accounts(3), 0);
+        builder.block_timestamp(100).prepaid_gas(Gas::from_tgas(100));
+        testing_env!(builder.build());
+        contract.swap(None, None);
+    }
+
+    #[test]
+    fn keepers_swap_full_batches() {
+        let mut contract = setup();
+        contract.set_batch_swap_threshold(3);
+        for i in 1..6 {
+            testing_env!(context(accounts(i), 2 * ONE_NEAR).block_timestamp(100).build());
+            contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        }
+        let pair = contract.internal_get_pair(DEFAULT_PAIR_ID);
+
+        // gas for one position is enough for the owner only
+        let mut builder = context(accounts(3), 0);
+        builder.block_timestamp(100).prepaid_gas(contract.batch_gas(&pair, 1));
+        testing_env!(builder.build());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.swap(None, None)));
+        assert!(result.is_err());
+
+        // a keeper takes the threshold, the positions left are batched next
+        builder.prepaid_gas(contract.batch_gas(&pair, 3));
+        testing_env!(builder.build());
+        contract.swap(None, None);
+        assert_eq!(contract.get_batch(0).unwrap().positions, vec![0, 1, 2]);
+        assert_eq!(contract.get_pending_swaps(None).forward, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "No positions are due")]
+    fn keepers_do_not_start_empty_batches() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(3), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+    }
+
+    #[test]
+    fn registered_keepers_are_bonded_and_tracked() {
+        let mut contract = setup();
+        contract.set_keeper_config(KeeperConfig { open: false, min_bond: U128(ONE_NEAR), cooldown: 0, ..KeeperConfig::default() });
+        contract.add_keeper(accounts(3));
+        testing_env!(context(accounts(3), 2 * ONE_NEAR).build());
+        assert_eq!(contract.deposit_keeper_bond(), U128(2 * ONE_NEAR));
+        assert_eq!(emitted_events()[0]["event"], "keeper_bond_deposited");
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 2), 50, None, None);
+
+        // one batch fails and the next one settles
+        testing_env!(context(accounts(3), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        assert_eq!(contract.get_keeper(accounts(3)).unwrap().pending_batches, vec![0]);
+        callback_env(110);
+        contract.pool_transfer_callback(0, Ok("0".to_string()));
+        testing_env!(context(accounts(3), 0).block_timestamp(200).build());
+        contract.swap(None, None);
+        callback_env(210);
+        withdraw_confirmed(&mut contract, 1);
+
+        let keeper = contract.get_keeper(accounts(3)).unwrap();
+        assert_eq!((keeper.batches_started, keeper.batches_settled, keeper.batches_failed), (2, 1, 1));
+        assert_eq!(keeper.last_batch_timestamp, 200);
+        assert_eq!(contract.get_keepers(None, None), vec![keeper]);
+
+        // the owner slashes part of the bond, the rest is returned after unbonding
+        testing_env!(context(accounts(0), 1).block_timestamp(300).build());
+        contract.slash_keeper(accounts(3), 0, U128(ONE_NEAR / 2), "failed batch 0".to_string());
+        assert_eq!(emitted_events()[0]["data"]["batch_id"], 0);
+        testing_env!(context(accounts(3), 0).block_timestamp(300).build());
+        contract.unregister_keeper();
+        assert_eq!(emitted_events()[0]["data"], near_sdk::serde_json::json!({"keeper": accounts(3), "unbonding_since": 300}));
+        let keeper = contract.get_keeper(accounts(3)).unwrap();
+        assert_eq!((keeper.bond, keeper.slashed), (U128(3 * ONE_NEAR / 2), U128(ONE_NEAR / 2)));
+
+        testing_env!(context(accounts(3), 0).block_timestamp(300 + KeeperConfig::default().unbonding_period).build());
+        contract.withdraw_keeper_bond();
+        assert!(contract.get_keeper(accounts(3)).is_none());
+        assert_eq!(emitted_events()[0]["data"], near_sdk::serde_json::json!({"keeper": accounts(3), "amount": (3 * ONE_NEAR / 2).to_string()}));
+    }
+
+    #[test]
+    fn keepers_are_listed_in_pages() {
+        let mut contract = setup();
+        for i in 0..150 {
+            testing_env!(context(accounts(0), 0).build());
+            contract.add_keeper(format!("keeper{}.near", i).parse().unwrap());
+        }
+
+        let page = contract.get_keepers(None, None);
+        assert_eq!(page.len(), DEFAULT_PAGE_LIMIT as usize);
+        assert_eq!(page[0].account_id, "keeper0.near".parse::<AccountId>().unwrap());
+        let page = contract.get_keepers(Some(10), Some(1_000));
+        assert_eq!(page.len(), MAX_PAGE_LIMIT as usize);
+        assert_eq!(page[0].account_id, "keeper10.near".parse::<AccountId>().unwrap());
+        assert_eq!(contract.get_keepers(Some(140), None).len(), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only failed batches are slashed")]
+    fn settled_batches_are_not_slashed() {
+        let mut contract = setup();
+        contract.set_keeper_config(KeeperConfig { open: false, min_bond: U128(ONE_NEAR), cooldown: 0, ..KeeperConfig::default() });
+        contract.add_keeper(accounts(3));
+        testing_env!(context(accounts(3), ONE_NEAR).build());
+        contract.deposit_keeper_bond();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 2), 50, None, None);
+        testing_env!(context(accounts(3), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        callback_env(110);
+        withdraw_confirmed(&mut contract, 0);
+
+        testing_env!(context(accounts(0), 1).block_timestamp(200).build());
+        contract.slash_keeper(accounts(3), 0, U128(ONE_NEAR / 2), "settled batch 0".to_string());
+    }
+
+    #[test]
+    fn stuck_batches_hold_the_bond_for_the_unbonding_period() {
+        let mut contract = setup();
+        let unbonding_period = KeeperConfig::default().unbonding_period;
+        contract.set_keeper_config(KeeperConfig { open: false, min_bond: U128(ONE_NEAR), cooldown: 0, ..KeeperConfig::default() });
+        contract.add_keeper(accounts(3));
+        testing_env!(context(accounts(3), ONE_NEAR).build());
+        contract.deposit_keeper_bond();
+
+        // the batch never gets a callback
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 2), 50, None, None);
+        testing_env!(context(accounts(3), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        contract.unregister_keeper();
+
+        testing_env!(context(accounts(3), 0).block_timestamp(99 + unbonding_period).build());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| contract.withdraw_keeper_bond()));
+        assert!(result.is_err());
+
+        testing_env!(context(accounts(3), 0).block_timestamp(100 + unbonding_period).build());
+        contract.withdraw_keeper_bond();
+        assert!(contract.get_keeper(accounts(3)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount exceeds the most slashed for a batch")]
+    fn slashing_is_capped() {
+        let mut contract = setup();
+        contract.set_keeper_config(KeeperConfig { open: false, min_bond: U128(ONE_NEAR), cooldown: 0, ..KeeperConfig::default() });
+        contract.add_keeper(accounts(3));
+        testing_env!(context(accounts(3), 3 * ONE_NEAR).build());
+        contract.deposit_keeper_bond();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR / 2), 50, None, None);
+        testing_env!(context(accounts(3), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+        callback_env(110);
+        contract.pool_transfer_callback(0, Ok("0".to_string()));
+
+        testing_env!(context(accounts(0), 1).block_timestamp(200).build());
+        contract.slash_keeper(accounts(3), 0, U128(2 * ONE_NEAR), "failed batch 0".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only registered keepers can swap")]
+    fn closed_keepers_must_be_registered() {
+        let mut contract = setup();
+        contract.set_keeper_config(KeeperConfig { open: false, ..KeeperConfig::default() });
+        contract.add_keeper(accounts(3));
+        contract.remove_keeper(accounts(3));
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(3), 0).block_timestamp(100).build());
+        contract.swap(None, None);
+    }
+
+    #[test]
+    fn reverse_positions_fund_with_the_token() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 0).block_timestamp(100).build());
+        contract.create_position(None, U128(100), 50, Some(true), None);
+        assert!(!contract.can_swap(None, Some(true)));
+
+        // deposits go through the token contract
+        let mut builder = context(accounts(1), 0);
+        builder.predecessor_account_id("token.near".parse().unwrap()).block_timestamp(100);
+        testing_env!(builder.build());
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(250), "".to_string())), 0);
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(50), "{\"topup\": {}}".to_string())), 0);
+        let position = contract.get_position(0);
+        assert_eq!(position.amount, U128(300));
+        assert_eq!(position.total_swapped, U128(0));
+        assert!(contract.can_swap(None, Some(true)));
+
+        // the token side is what withdraw_ft takes from
+        testing_env!(context(accounts(1), 1).block_timestamp(100).build());
+        contract.withdraw_ft(0, "token.near".parse().unwrap(), U128(250));
+        assert_eq!(contract.get_position(0).amount, U128(50));
+        assert!(!contract.can_swap(None, Some(true)));
+    }
+
+    #[test]
+    fn transfer_messages_register_or_refund() {
+        let mut contract = setup();
+
+        let mut builder = context(accounts(1), 0);
+        builder.predecessor_account_id("token.near".parse().unwrap()).block_timestamp(100);
+        testing_env!(builder.build());
+
+        // topup before registering
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(500), "".to_string())), 500);
+
+        let register = "{\"register\": {\"amount_per_swap\": \"100\", \"swap_interval\": 50, \"max_slippage_bps\": 30}}";
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(500), register.to_string())), 0);
+        let position = contract.get_position(0);
+        assert!(position.reverse);
+        assert_eq!(position.amount, U128(500));
+        assert_eq!(position.amount_per_swap, U128(100));
+        assert_eq!(position.max_slippage_bps, 30);
+        assert!(contract.can_swap(None, Some(true)));
+
+        // deposits to the position of another account, unknown and malformed messages
+        // are refunded
+        let topup_position = "{\"topup_position\": {\"position_id\": 0}}";
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(2), U128(10), topup_position.to_string())), 10);
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(10), "{\"close\": {}}".to_string())), 10);
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(10), "topup".to_string())), 10);
+        assert_eq!(contract.get_position(0).amount, U128(500));
+    }
+
+    #[test]
+    #[should_panic(expected = "Positions selling a token deposit it with ft_transfer_call")]
+    fn reverse_positions_do_not_attach_near() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), ONE_NEAR).build());
+        contract.create_position(None, U128(100), 50, Some(true), None);
+    }
+
+    #[test]
+    fn accounts_hold_several_positions() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        assert_eq!(contract.create_position(None, U128(ONE_NEAR / 10), 10, None, None), 0);
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(100).build());
+        assert_eq!(contract.create_position(None, U128(ONE_NEAR / 10), 10, None, None), 1);
+        testing_env!(context(accounts(1), 3 * ONE_NEAR).block_timestamp(100).build());
+        assert_eq!(contract.create_position(None, U128(ONE_NEAR), 70, None, None), 2);
+
+        let ids: Vec<PositionId> = contract.get_positions(accounts(1)).iter().map(|position| position.id).collect();
+        assert_eq!(ids, vec![0, 2]);
+        assert_eq!(contract.get_pending_swaps(None).forward, 3);
+
+        // each position is paused, funded and closed on its own
+        contract.pause(0);
+        assert!(!contract.get_position(2).pause);
+        assert_eq!(contract.get_pending_swaps(None).forward, 2);
+        contract.topup(2);
+        assert_eq!(contract.get_position(0).amount, U128(2 * ONE_NEAR));
+        assert_eq!(contract.get_position(2).amount, U128(6 * ONE_NEAR));
+
+        testing_env!(context(accounts(1), 1).block_timestamp(100).build());
+        contract.close_position(2);
+        let ids: Vec<PositionId> = contract.get_positions(accounts(1)).iter().map(|position| position.id).collect();
+        assert_eq!(ids, vec![0]);
+        assert_eq!(contract.get_pending_swaps(None).forward, 1);
+
+        // with a second reverse position the plain topup no longer knows which one to fund
+        let mut builder = context(accounts(1), 0);
+        builder.predecessor_account_id("token.near".parse().unwrap()).block_timestamp(100);
+        testing_env!(builder.build());
+        let register = "{\"register\": {\"amount_per_swap\": \"100\", \"swap_interval\": 50}}";
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(100), register.to_string())), 0);
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(100), "".to_string())), 0);
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(100), register.to_string())), 0);
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(100), "".to_string())), 100);
+        let topup_position = "{\"topup_position\": {\"position_id\": 4}}";
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(100), topup_position.to_string())), 0);
+        assert_eq!(contract.get_position(3).amount, U128(200));
+        assert_eq!(contract.get_position(4).amount, U128(200));
+        assert_eq!(contract.get_positions(accounts(1)).len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Position belongs to another account")]
+    fn positions_are_changed_only_by_their_account() {
+        let mut contract = setup();
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).build());
+        contract.create_position(None, U128(ONE_NEAR / 10), 60, None, None);
+
+        testing_env!(context(accounts(2), 1).build());
+        contract.withdraw_near(0, U128(ONE_NEAR));
+    }
+
+    #[test]
+    fn batches_are_grouped_per_pair() {
+        let mut contract = setup();
+        assert_eq!(contract.add_pair("wrap.near".parse().unwrap(), "usdc.near".parse().unwrap(), ref_venue(), 7), 1);
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        contract.create_position(Some(1), U128(ONE_NEAR), 50, None, None);
+        testing_env!(context(accounts(2), 2 * ONE_NEAR).block_timestamp(100).build());
+        contract.create_position(Some(1), U128(ONE_NEAR), 50, None, None);
+        assert_eq!(contract.get_pending_swaps(None).forward, 1);
+        assert_eq!(contract.get_pending_swaps(Some(1)).forward, 2);
+
+        testing_env!(context(accounts(0), 0).block_timestamp(100).build());
+        contract.swap(Some(1), None);
+        let batch = contract.get_batch(0).unwrap();
+        assert_eq!(batch.pair_id, 1);
+        assert_eq!(batch.positions, vec![1, 2]);
+        assert_eq!(contract.batch_tokens(&batch), ("wrap.near".parse().unwrap(), "usdc.near".parse().unwrap()));
+
+        // the default pair uses the same wNEAR deposit on Ref as the batch in flight
+        assert!(!contract.can_swap(None, None));
+        quote_env(110);
+        contract.pool_quote_callback(0, 0, U128(ONE_NEAR), Err(PromiseError::Failed), Err(PromiseError::Failed), Err(PromiseError::Failed));
+
+        // a disabled pair is not swapped
+        testing_env!(context(accounts(0), 0).block_timestamp(120).build());
+        contract.set_pair_enabled(1, false);
+        assert!(contract.can_swap(None, None));
+        assert!(!contract.can_swap(Some(1), None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Pair is disabled")]
+    fn disabled_pairs_take_no_new_positions() {
+        let mut contract = setup();
+        contract.set_pair_enabled(0, false);
+
+        testing_env!(context(accounts(1), 2 * ONE_NEAR).build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+    }
+
+    #[test]
+    fn pairs_without_wnear_are_funded_with_their_token() {
+        let mut contract = setup();
+        let usdc: AccountId = "usdc.near".parse().unwrap();
+        let eth: AccountId = "eth.near".parse().unwrap();
+        contract.add_pair(usdc.clone(), eth.clone(), ref_venue(), 3);
+
+        // the deposited token is the one sold, usdc is token_in so the position is forward
+        let mut builder = context(accounts(1), 0);
+        builder.predecessor_account_id(usdc.clone()).block_timestamp(100);
+        testing_env!(builder.build());
+        let register = "{\"register\": {\"pair_id\": 1, \"amount_per_swap\": \"100\", \"swap_interval\": 50}}";
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(300), register.to_string())), 0);
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(100), "".to_string())), 0);
+        let position = contract.get_position(0);
+        assert!(!position.reverse);
+        assert_eq!(position.amount, U128(400));
+        assert!(contract.can_swap(Some(1), None));
+
+        // the token of the pair that is bought does not fund the position
+        builder.predecessor_account_id(eth.clone());
+        testing_env!(builder.build());
+        let topup_position = "{\"topup_position\": {\"position_id\": 0}}";
+        assert_eq!(refunded(contract.ft_on_transfer(accounts(1), U128(100), topup_position.to_string())), 100);
+
+        // neither side is wNEAR, so both are withdrawn as tokens
+        let mut position = contract.get_position(0);
+        position.total_swapped = U128(5);
+        contract.internal_save_position(position);
+        testing_env!(context(accounts(1), 1).block_timestamp(100).build());
+        contract.close_position(0);
+        let receivers: Vec<AccountId> = get_created_receipts().into_iter().map(|receipt| receipt.receiver_id).collect();
+        assert!(receivers.contains(&usdc) && receivers.contains(&eth));
+        assert!(!receivers.contains(&"wrap.near".parse().unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not traded by any pair")]
+    fn tokens_outside_the_pairs_are_rejected() {
+        let mut contract = setup();
+
+        let mut builder = context(accounts(1), 0);
+        builder.predecessor_account_id("other.near".parse().unwrap());
+        testing_env!(builder.build());
+        contract.ft_on_transfer(accounts(1), U128(100), "".to_string());
+    }
+
+    #[test]
+    fn contract_accounts_own_positions() {
+        let mut contract = setup();
+        let dao: AccountId = "treasury.sputnik-dao.near".parse().unwrap();
+
+        // a member of the DAO signs, the DAO contract makes the call
+        let mut builder = context(accounts(1), 2 * ONE_NEAR);
+        builder.predecessor_account_id(dao.clone()).block_timestamp(100);
+        testing_env!(builder.build());
+        contract.create_position(None, U128(ONE_NEAR), 50, None, None);
+        assert_eq!(contract.get_positions(dao.clone()).len(), 1);
+        assert!(contract.get_positions(accounts(1)).is_empty());
+
+        contract.pause(0);
+        assert!(contract.get_position(0).pause);
+
+        // withdrawals are sent back to the DAO
+        testing_env!(builder.attached_deposit(NearToken::from_yoctonear(1)).build());
+        contract.withdraw_near(0, U128(ONE_NEAR));
+        assert!(get_created_receipts().iter().any(|receipt| receipt.receiver_id == dao));
+        assert!(get_created_receipts().iter().all(|receipt| receipt.receiver_id != accounts(1)));
+    }
+
+    #[test]
+    fn a_contract_can_be_the_owner() {
+        let dao: AccountId = "treasury.sputnik-dao.near".parse().unwrap();
+        let mut contract = setup();
+        contract.owner = dao.clone();
+
+        let mut builder = context(accounts(1), 0);
+        builder.predecessor_account_id(dao);
+        testing_env!(builder.build());
+        contract.set_batch_swap_threshold(3);
+        assert_eq!(contract.get_batch_swap_threshold(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn the_signer_of_a_call_is_not_the_owner() {
+        let mut contract = setup();
+
+        // the owner signs, but the call comes from another contract
+        let mut builder = context(accounts(0), 0);
+        builder.predecessor_account_id(accounts(2));
+        testing_env!(builder.build());
+        contract.set_batch_swap_threshold(3);
+    }
+
+    #[test]
+    fn migrate_keeps_balances() {
+        testing_env!(context("dca.near".parse().unwrap(), 0).build());
+        let user = OldUser {
+            wallet: accounts(1),
+            amount_per_swap: U128(10),
+            swap_interval: 60,
+            last_swap_timestamp: 42,
+            total_swapped: U128(7),
+            amount: U128(100),
+            pause: false,
+            reverse: false,
+        };
+        let old_state = OldContract {
+            users: HashMap::from([(accounts(1), user)]),
+            user_addresses: vec![accounts(1)],
+            batch_swap_threshold: 5,
+            token_address: "token.near".parse().unwrap(),
+            owner: accounts(0),
+            fees: 10,
+            wrap_account: "wrap.near".parse().unwrap(),
+            pool_id: 1,
+            pool_address: "ref.near".parse().unwrap(),
+        };
+        env::state_write(&old_state);
+
+        let contract = Contract::migrate();
+        let migrated = contract.get_position(0);
+        assert_eq!(migrated.wallet, accounts(1));
+        assert_eq!(contract.get_positions(accounts(1)).len(), 1);
+        assert_eq!(migrated.amount, U128(100));
+        assert_eq!(migrated.total_swapped, U128(7));
+        assert_eq!(migrated.last_swap_timestamp, 42);
+        assert_eq!(migrated.max_slippage_bps, DEFAULT_MAX_SLIPPAGE_BPS);
+        assert_eq!(contract.get_pair(DEFAULT_PAIR_ID).venue, ref_venue());
+        assert_eq!(contract.get_batch_swap_threshold(), 5);
+    }
 }
-    
\ No newline at end of file
@@ -0,0 +1,79 @@
+// 256-bit integer used to keep intermediate products of u128 amounts exact
+#[allow(clippy::manual_div_ceil)]
+mod u256 {
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+}
+
+pub use u256::U256;
+
+pub const BPS_DENOMINATOR: u128 = 10_000;
+// Scale of prices, which are fractions of two amounts of different tokens
+pub const PRICE_PRECISION: u128 = 1_000_000_000_000_000_000;
+
+/// Computes `a * b / c` without overflowing the intermediate product.
+pub fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    assert!(c > 0, "Division by zero");
+    (U256::from(a) * U256::from(b) / U256::from(c)).as_u128()
+}
+
+/// Price impact, in basis points, of trading `amount` for `quote` compared to the
+/// price obtained trading `reference_amount` for `reference_quote`.
+pub fn price_impact_bps(amount: u128, quote: u128, reference_amount: u128, reference_quote: u128) -> u128 {
+    if amount == 0 || reference_quote == 0 {
+        return BPS_DENOMINATOR;
+    }
+    // quote / amount compared to reference_quote / reference_amount
+    let reference_output = U256::from(reference_quote) * U256::from(amount);
+    let output = U256::from(quote) * U256::from(reference_amount);
+    if output >= reference_output {
+        return 0;
+    }
+    ((reference_output - output) * U256::from(BPS_DENOMINATOR) / reference_output).as_u128()
+}
+
+/// Minimum output accepted when trading `amount` at the reference price with the
+/// given slippage tolerance.
+pub fn min_amount_out(amount: u128, reference_amount: u128, reference_quote: u128, slippage_bps: u16) -> u128 {
+    let expected = mul_div(amount, reference_quote, reference_amount);
+    mul_div(expected, BPS_DENOMINATOR - u128::from(slippage_bps.min(10_000)), BPS_DENOMINATOR)
+}
+
+/// Amount of input paid for one unit of output, scaled by `PRICE_PRECISION`, both in
+/// the smallest units of their tokens. None without output or if it overflows.
+pub fn average_price(amount_in: u128, amount_out: u128) -> Option<u128> {
+    if amount_out == 0 {
+        return None;
+    }
+    let price = U256::from(amount_in) * U256::from(PRICE_PRECISION) / U256::from(amount_out);
+    (price <= U256::from(u128::MAX)).then(|| price.as_u128())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mul_div_does_not_overflow() {
+        let amount = 1_000 * 10u128.pow(24);
+        assert_eq!(mul_div(amount, amount, amount), amount);
+    }
+
+    #[test]
+    fn price_impact() {
+        // same price as the reference
+        assert_eq!(price_impact_bps(100, 200, 10, 20), 0);
+        // 2% worse than the reference
+        assert_eq!(price_impact_bps(100, 196, 10, 20), 200);
+        assert_eq!(price_impact_bps(100, 0, 10, 0), 10_000);
+        assert_eq!(min_amount_out(100, 10, 20, 150), 197);
+    }
+
+    #[test]
+    fn average_price_of_the_output() {
+        assert_eq!(average_price(300, 200), Some(PRICE_PRECISION * 3 / 2));
+        assert_eq!(average_price(300, 0), None);
+        assert_eq!(average_price(u128::MAX, 1), None);
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use near_sdk::json_types::U128;
+use near_sdk::{near, AccountId};
+
+use crate::batch::Batch;
+use crate::pair::PairId;
+use crate::position::Position;
+use crate::{Contract, ContractExt};
+
+// Input and output of the settled batches of one direction of a pair
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PairVolume {
+    pub pair_id: PairId,
+    pub reverse: bool,
+    // input of the positions, fee included
+    pub amount_in: U128,
+    pub amount_out: U128,
+    pub batches: u64,
+}
+
+// Counters of the whole contract, kept up to date as positions and batches change
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    // accounts holding at least one position
+    pub accounts: u64,
+    pub active_positions: u64,
+    pub paused_positions: u64,
+    // balances of the positions per token, sold and bought, until withdrawn
+    pub deposits: HashMap<AccountId, U128>,
+    pub volumes: Vec<PairVolume>,
+    // protocol fees of the settled batches per token, keeper bounties included
+    pub fees: HashMap<AccountId, U128>,
+    pub batches_settled: u64,
+    pub batches_failed: u64,
+    // when the last batch was started
+    pub last_batch_timestamp: u64,
+}
+
+fn add(totals: &mut HashMap<AccountId, U128>, token_id: &AccountId, amount: u128) {
+    let total = totals.entry(token_id.clone()).or_insert(U128(0));
+    total.0 = total.0.checked_add(amount).expect("Overflow");
+}
+
+fn sub(totals: &mut HashMap<AccountId, U128>, token_id: &AccountId, amount: u128) {
+    let total = totals.entry(token_id.clone()).or_insert(U128(0));
+    total.0 = total.0.checked_sub(amount).expect("Deposits underflow");
+}
+
+impl Contract {
+    /// Moves the position counters and deposits from the previous state of a
+    /// position to the current one. None stands for a position that does not exist.
+    pub(crate) fn internal_track_position(&mut self, previous: Option<&Position>, current: Option<&Position>) {
+        if let Some(position) = current {
+            let (token_sold, token_bought) = self.position_tokens(position);
+            add(&mut self.metrics.deposits, &token_sold, position.amount.0);
+            add(&mut self.metrics.deposits, &token_bought, position.total_swapped.0);
+            if position.pause {
+                self.metrics.paused_positions += 1;
+            } else {
+                self.metrics.active_positions += 1;
+            }
+        }
+        if let Some(position) = previous {
+            let (token_sold, token_bought) = self.position_tokens(position);
+            sub(&mut self.metrics.deposits, &token_sold, position.amount.0);
+            sub(&mut self.metrics.deposits, &token_bought, position.total_swapped.0);
+            if position.pause {
+                self.metrics.paused_positions -= 1;
+            } else {
+                self.metrics.active_positions -= 1;
+            }
+        }
+    }
+
+    /// Counts the volume and the fee of a batch that settled.
+    pub(crate) fn internal_track_settled_batch(&mut self, batch: &Batch, token_in: &AccountId) {
+        let volumes = &mut self.metrics.volumes;
+        let index = match volumes.iter().position(|volume| volume.pair_id == batch.pair_id && volume.reverse == batch.reverse) {
+            Some(index) => index,
+            None => {
+                volumes.push(PairVolume { pair_id: batch.pair_id, reverse: batch.reverse, amount_in: U128(0), amount_out: U128(0), batches: 0 });
+                volumes.len() - 1
+            }
+        };
+        let volume = &mut volumes[index];
+        volume.amount_in = U128(volume.amount_in.0.checked_add(batch.amount.0).expect("Overflow"));
+        volume.amount_out = U128(volume.amount_out.0.checked_add(batch.amount_out.0).expect("Overflow"));
+        volume.batches += 1;
+
+        add(&mut self.metrics.fees, token_in, batch.fee.0);
+        self.metrics.batches_settled += 1;
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn get_metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+}
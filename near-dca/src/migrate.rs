@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use near_sdk::store::{IterableMap, LookupMap, TreeMap};
+use near_sdk::{env, near, AccountId};
+
+use crate::ext::Venue;
+use crate::keeper::KeeperConfig;
+use crate::metrics::Metrics;
+use crate::pair::DEFAULT_PAIR_ID;
+use crate::position::Position;
+use crate::{Contract, ContractExt, StorageKey, DEFAULT_GAS_PER_POSITION, DEFAULT_MAX_SLIPPAGE_BPS};
+use near_sdk::json_types::U128;
+
+// Layout of a user before positions and their settings were added. Each user
+// becomes a position of its account.
+#[near(serializers = [borsh])]
+pub struct OldUser {
+    pub wallet: AccountId,
+    pub amount_per_swap: U128,
+    pub swap_interval: u64,
+    pub last_swap_timestamp: u64,
+    pub total_swapped: U128,
+    pub amount: U128,
+    pub pause: bool,
+    pub reverse: bool,
+}
+
+impl From<OldUser> for Position {
+    fn from(user: OldUser) -> Self {
+        Self {
+            // assigned when the position is added
+            id: 0,
+            wallet: user.wallet,
+            // the only market of the old contract
+            pair_id: DEFAULT_PAIR_ID,
+            amount_per_swap: user.amount_per_swap,
+            swap_interval: user.swap_interval,
+            last_swap_timestamp: user.last_swap_timestamp,
+            total_swapped: user.total_swapped,
+            amount: user.amount,
+            pause: user.pause,
+            reverse: user.reverse,
+            max_slippage_bps: DEFAULT_MAX_SLIPPAGE_BPS,
+            in_flight: None,
+        }
+    }
+}
+
+// Layout of the contract state before users were moved to persistent collections.
+// Every user lived inside the root state object, together with a parallel list of
+// their addresses.
+#[near(serializers = [borsh])]
+pub struct OldContract {
+    pub users: HashMap<AccountId, OldUser>,
+    pub user_addresses: Vec<AccountId>,
+    pub batch_swap_threshold: u8,
+    pub token_address: AccountId,
+    pub owner: AccountId,
+    pub fees: u8,
+    pub wrap_account: AccountId,
+    pub pool_id: u16,
+    pub pool_address: AccountId,
+}
+
+#[near]
+impl Contract {
+    /// Converts the state of an already deployed contract to the current layout.
+    /// Must be called once, right after deploying the new code.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old_state: OldContract = env::state_read().expect("Failed to read the old state");
+
+        let mut contract = Self {
+            positions: IterableMap::new(StorageKey::Positions),
+            account_positions: LookupMap::new(StorageKey::AccountPositions),
+            next_position_id: 0,
+            due: TreeMap::new(StorageKey::Due),
+            pairs: IterableMap::new(StorageKey::Pairs),
+            next_pair_id: 0,
+            batches: LookupMap::new(StorageKey::Batches),
+            next_batch_id: 0,
+            dust: IterableMap::new(StorageKey::Dust),
+            accrued_fees: IterableMap::new(StorageKey::AccruedFees),
+            keeper_rewards: LookupMap::new(StorageKey::KeeperRewards),
+            last_batch_timestamps: LookupMap::new(StorageKey::LastBatchTimestamps),
+            pool_locks: LookupMap::new(StorageKey::PoolLocks),
+            keeper_config: KeeperConfig::default(),
+            keepers: IterableMap::new(StorageKey::Keepers),
+            user_stats: LookupMap::new(StorageKey::UserStats),
+            metrics: Metrics::default(),
+            gas_per_position: DEFAULT_GAS_PER_POSITION,
+            batch_swap_threshold: old_state.batch_swap_threshold,
+            owner: old_state.owner,
+            fees: old_state.fees,
+            wrap_account: old_state.wrap_account,
+        };
+        // the token and pool of the old contract become the default pair, on the Ref
+        // contract it was swapped on
+        let venue = Venue::RefV2 { contract_id: old_state.pool_address };
+        contract.internal_add_pair(contract.wrap_account.clone(), old_state.token_address, venue, old_state.pool_id.into());
+
+        // the list of addresses keeps the order users registered in, so position ids
+        // follow it
+        let mut old_users = old_state.users;
+        for account_id in old_state.user_addresses {
+            if let Some(user) = old_users.remove(&account_id) {
+                contract.internal_add_position(user.into());
+            }
+        }
+        // users missing from the list still hold balances
+        let mut remaining: Vec<OldUser> = old_users.into_values().collect();
+        remaining.sort_by(|a, b| a.wallet.cmp(&b.wallet));
+        for user in remaining {
+            contract.internal_add_position(user.into());
+        }
+
+        contract
+    }
+}
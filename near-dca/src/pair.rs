@@ -0,0 +1,157 @@
+use near_sdk::{near, AccountId};
+
+use crate::events::DcaEvent;
+use crate::ext::Venue;
+use crate::{Contract, ContractExt, DEFAULT_GAS_PER_POSITION, GAS_FOR_BATCH_POSITION, GAS_FOR_QUOTE_CALLBACK, GAS_FOR_QUOTE_HOP, GAS_FOR_SETTLE_RESERVE, GAS_FOR_SWAP, MAX_TRANSACTION_GAS};
+
+pub type PairId = u32;
+
+// Pair set up at init from the token and pool of the contract, used when a call does
+// not name a pair
+pub const DEFAULT_PAIR_ID: PairId = 0;
+// Longest route a pair can be traded through: every hop is quoted in the pipeline of
+// the batch, which must still fit a position in the gas of one transaction
+pub const MAX_ROUTE_HOPS: usize = 1 + (MAX_TRANSACTION_GAS
+    .saturating_sub(GAS_FOR_SWAP)
+    .saturating_sub(GAS_FOR_QUOTE_CALLBACK)
+    .saturating_sub(GAS_FOR_SETTLE_RESERVE)
+    .saturating_sub(DEFAULT_GAS_PER_POSITION)
+    .saturating_sub(GAS_FOR_BATCH_POSITION)
+    .as_gas() / GAS_FOR_QUOTE_HOP.as_gas()) as usize;
+
+// A swap through one pool of the venue of the pair
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hop {
+    // index of the pool on Ref and Jumbo, fee of the pool on DCL where pools are
+    // keyed by their tokens and fee
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+}
+
+// A market positions can be opened on. Forward positions sell token_in for
+// token_out, reverse positions sell token_out for token_in.
+#[near(serializers = [json, borsh])]
+#[derive(Clone)]
+pub struct Pair {
+    pub id: PairId,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    // DEX the pair is swapped on
+    pub venue: Venue,
+    // pools of the venue going from token_in to token_out, reversed for reverse positions
+    pub route: Vec<Hop>,
+    // disabled pairs take no new positions and are not swapped, existing positions
+    // can still be withdrawn
+    pub enabled: bool,
+}
+
+impl Pair {
+    /// Tokens sold and bought in the given direction.
+    pub fn tokens(&self, reverse: bool) -> (AccountId, AccountId) {
+        if !reverse {
+            (self.token_in.clone(), self.token_out.clone())
+        } else {
+            (self.token_out.clone(), self.token_in.clone())
+        }
+    }
+
+    /// Hops swapping the tokens sold for the tokens bought in the given direction.
+    pub fn route(&self, reverse: bool) -> Vec<Hop> {
+        if !reverse {
+            self.route.clone()
+        } else {
+            self.route
+                .iter()
+                .rev()
+                .map(|hop| Hop { pool_id: hop.pool_id, token_in: hop.token_out.clone(), token_out: hop.token_in.clone() })
+                .collect()
+        }
+    }
+}
+
+/// Checks that the route goes from token_in to token_out, each hop starting from the
+/// token the previous one ends with.
+fn check_route(token_in: &AccountId, token_out: &AccountId, route: &[Hop]) {
+    assert!(!route.is_empty(), "Route must have at least one hop");
+    assert!(route.len() <= MAX_ROUTE_HOPS, "Route must have at most {} hops", MAX_ROUTE_HOPS);
+
+    let mut token = token_in;
+    for hop in route {
+        assert_eq!(&hop.token_in, token, "Route does not connect at {}", token);
+        assert_ne!(hop.token_in, hop.token_out, "A hop trades two different tokens");
+        token = &hop.token_out;
+    }
+    assert_eq!(token, token_out, "Route does not end with {}", token_out);
+}
+
+impl Contract {
+    /// Adds a pair traded directly through one pool of the venue.
+    pub(crate) fn internal_add_pair(&mut self, token_in: AccountId, token_out: AccountId, venue: Venue, pool_id: u64) -> PairId {
+        assert_ne!(token_in, token_out, "A pair trades two different tokens");
+        let route = vec![Hop { pool_id, token_in: token_in.clone(), token_out: token_out.clone() }];
+        let exists = self.pairs.values().any(|pair| pair.token_in == token_in && pair.token_out == token_out && pair.venue == venue && pair.route == route);
+        assert!(!exists, "Pair already exists");
+
+        let pair_id = self.next_pair_id;
+        self.next_pair_id += 1;
+        self.pairs.insert(pair_id, Pair { id: pair_id, token_in, token_out, venue, route, enabled: true });
+
+        pair_id
+    }
+
+    pub(crate) fn internal_get_pair(&self, pair_id: PairId) -> Pair {
+        self.pairs.get(&pair_id).expect("Pair does not exist").clone()
+    }
+}
+
+#[near]
+impl Contract {
+    #[payable]
+    pub fn add_pair(&mut self, token_in: AccountId, token_out: AccountId, venue: Venue, pool_id: u64) -> PairId {
+        self.assert_owner();
+        let pair_id = self.internal_add_pair(token_in, token_out, venue, pool_id);
+        DcaEvent::config_changed("pair", &self.pairs[&pair_id]);
+        pair_id
+    }
+
+    // Routes the pair through other pools of its venue, e.g. wNEAR -> USDC -> token when
+    // there is no pool between its two tokens. Batches already quoted keep their route.
+    #[payable]
+    pub fn set_pair_route(&mut self, pair_id: PairId, route: Vec<Hop>) {
+        self.assert_owner();
+        let pair = self.pairs.get_mut(&pair_id).expect("Pair does not exist");
+        check_route(&pair.token_in, &pair.token_out, &route);
+        pair.route = route;
+        DcaEvent::config_changed("pair", pair);
+    }
+
+    // Moves the pair to another venue, with a route through the pools of that venue.
+    // Batches already quoted finish on their venue.
+    #[payable]
+    pub fn set_pair_venue(&mut self, pair_id: PairId, venue: Venue, route: Vec<Hop>) {
+        self.assert_owner();
+        let pair = self.pairs.get_mut(&pair_id).expect("Pair does not exist");
+        check_route(&pair.token_in, &pair.token_out, &route);
+        pair.venue = venue;
+        pair.route = route;
+        DcaEvent::config_changed("pair", pair);
+    }
+
+    #[payable]
+    pub fn set_pair_enabled(&mut self, pair_id: PairId, enabled: bool) {
+        self.assert_owner();
+        let pair = self.pairs.get_mut(&pair_id).expect("Pair does not exist");
+        pair.enabled = enabled;
+        DcaEvent::config_changed("pair", pair);
+    }
+
+    pub fn get_pair(&self, pair_id: PairId) -> Pair {
+        self.internal_get_pair(pair_id)
+    }
+
+    pub fn get_pairs(&self) -> Vec<Pair> {
+        self.pairs.values().cloned().collect()
+    }
+}
@@ -0,0 +1,144 @@
+use near_sdk::json_types::U128;
+use near_sdk::{env, near, AccountId};
+
+use crate::batch::BatchId;
+use crate::events::DcaEvent;
+use crate::math::BPS_DENOMINATOR;
+use crate::pair::PairId;
+use crate::Contract;
+
+pub type PositionId = u64;
+
+// A DCA schedule of an account on one pair. An account can hold any number of
+// positions, on any pair and in both directions.
+#[near(serializers = [json, borsh])]
+#[derive(Clone)]
+pub struct Position {
+    pub id: PositionId,
+    // account owning the position, credited on withdrawals
+    pub wallet: AccountId,
+    pub pair_id: PairId,
+    pub amount_per_swap: U128,
+    pub swap_interval: u64,
+    pub last_swap_timestamp: u64,
+    // balance of the token bought: token_out of the pair, or token_in in reverse mode
+    pub total_swapped: U128,
+    // balance of the token sold: token_in of the pair, or token_out in reverse mode
+    pub amount: U128,
+    pub pause: bool,
+    pub reverse: bool,
+    // worst price accepted for a swap, in basis points below the quoted price
+    pub max_slippage_bps: u16,
+    // batch the position is part of. Its amount_per_swap is reserved, and the position
+    // is not batched again, until the batch settles or fails.
+    pub in_flight: Option<BatchId>,
+}
+
+impl Position {
+    /// Balance the position holds in the given token, given the tokens it sells and
+    /// buys. None if the position does not trade the token.
+    pub(crate) fn balance_mut(&mut self, (token_sold, token_bought): &(AccountId, AccountId), token_id: &AccountId) -> Option<&mut U128> {
+        if token_id == token_sold {
+            Some(&mut self.amount)
+        } else if token_id == token_bought {
+            Some(&mut self.total_swapped)
+        } else {
+            None
+        }
+    }
+
+    /// Part of the balance in the given token that can be withdrawn, which leaves out
+    /// the swap of a batch in flight. None if the position does not trade the token.
+    pub(crate) fn withdrawable(&self, (token_sold, token_bought): &(AccountId, AccountId), token_id: &AccountId) -> Option<u128> {
+        if token_id == token_sold {
+            let reserved = if self.in_flight.is_some() { self.amount_per_swap.0 } else { 0 };
+            Some(self.amount.0.saturating_sub(reserved))
+        } else if token_id == token_bought {
+            Some(self.total_swapped.0)
+        } else {
+            None
+        }
+    }
+}
+
+impl Contract {
+    /// Checks the settings of a new position.
+    pub(crate) fn check_position_settings(&self, pair_id: PairId, amount_per_swap: U128, max_slippage_bps: u16) -> Result<(), &'static str> {
+        let pair = self.pairs.get(&pair_id).ok_or("Pair does not exist")?;
+        if !pair.enabled {
+            return Err("Pair is disabled");
+        }
+        if amount_per_swap.0 == 0 {
+            return Err("Swap amount must be greater than 0");
+        }
+        if u128::from(max_slippage_bps) > BPS_DENOMINATOR {
+            return Err("Slippage must be at most 10000 basis points");
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn internal_create_position(&mut self, wallet: AccountId, pair_id: PairId, amount_per_swap: U128, swap_interval: u64, reverse: bool, max_slippage_bps: u16, amount: u128) -> PositionId {
+        let position_id = self.internal_add_position(Position {
+            id: 0,
+            wallet: wallet.clone(),
+            pair_id,
+            amount_per_swap,
+            swap_interval,
+            last_swap_timestamp: 0,
+            total_swapped: U128(0),
+            amount: U128(amount),
+            pause: false,
+            reverse,
+            max_slippage_bps,
+            in_flight: None,
+        });
+        DcaEvent::PositionCreated {
+            position_id,
+            account_id: &wallet,
+            pair_id,
+            reverse,
+            amount_per_swap,
+            swap_interval,
+            amount: U128(amount),
+        }.emit();
+        position_id
+    }
+
+    /// Stores a new position under the next id and lists it under its account.
+    pub(crate) fn internal_add_position(&mut self, mut position: Position) -> PositionId {
+        let position_id = self.next_position_id;
+        self.next_position_id += 1;
+        position.id = position_id;
+
+        let position_ids = self.account_positions.entry(position.wallet.clone()).or_default();
+        if position_ids.is_empty() {
+            self.metrics.accounts += 1;
+        }
+        position_ids.push(position_id);
+        self.internal_save_position(position);
+
+        position_id
+    }
+
+    pub(crate) fn internal_get_position(&self, position_id: PositionId) -> Position {
+        self.positions.get(&position_id).expect("Position does not exist").clone()
+    }
+
+    /// Position of the caller, the only account that can change it.
+    pub(crate) fn internal_get_own_position(&self, position_id: PositionId) -> Position {
+        let position = self.internal_get_position(position_id);
+        assert_eq!(position.wallet, env::predecessor_account_id(), "Position belongs to another account");
+        position
+    }
+
+    /// Tokens sold and bought by the position.
+    pub(crate) fn position_tokens(&self, position: &Position) -> (AccountId, AccountId) {
+        self.internal_get_pair(position.pair_id).tokens(position.reverse)
+    }
+
+    /// Ids of the positions of the account, in the order they were created.
+    pub(crate) fn account_position_ids(&self, account_id: &AccountId) -> Vec<PositionId> {
+        self.account_positions.get(account_id).cloned().unwrap_or_default()
+    }
+}
@@ -0,0 +1,124 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json;
+use near_sdk::{env, log, near, AccountId, PromiseOrValue};
+
+use crate::batch::BatchId;
+use crate::events::DcaEvent;
+use crate::pair::{PairId, DEFAULT_PAIR_ID};
+use crate::position::PositionId;
+use crate::{Contract, ContractExt, DEFAULT_MAX_SLIPPAGE_BPS};
+
+// Settings of a position opened with its first token deposit. The position sells the
+// deposited token, so it is a reverse position when that is token_out of the pair.
+#[near(serializers = [json])]
+pub struct RegisterArgs {
+    pub pair_id: Option<PairId>,
+    pub amount_per_swap: U128,
+    pub swap_interval: u64,
+    pub max_slippage_bps: Option<u16>,
+}
+
+// Commands accepted in the `msg` of ft_transfer_call, e.g.
+// `{"register": {"amount_per_swap": "100", "swap_interval": 86400000000000}}`,
+// `{"topup_position": {"position_id": 3}}` or `{"topup": {}}`.
+// `topup` and an empty `msg` fund the only position of the sender selling the token.
+// `batch_output` is echoed back by venues that send the output of a batch themselves.
+#[near(serializers = [json])]
+#[serde(rename_all = "snake_case")]
+pub enum TransferMessage {
+    Register(RegisterArgs),
+    TopupPosition { position_id: PositionId },
+    Topup {},
+    BatchOutput { batch_id: BatchId },
+}
+
+impl Contract {
+    fn internal_on_transfer(&mut self, token_id: &AccountId, sender_id: AccountId, amount: U128, message: TransferMessage) -> Result<(), &'static str> {
+        match message {
+            TransferMessage::Register(args) => {
+                let pair_id = args.pair_id.unwrap_or(DEFAULT_PAIR_ID);
+                let max_slippage_bps = args.max_slippage_bps.unwrap_or(DEFAULT_MAX_SLIPPAGE_BPS);
+                self.check_position_settings(pair_id, args.amount_per_swap, max_slippage_bps)?;
+                let pair = self.internal_get_pair(pair_id);
+                let reverse = if token_id == &pair.token_in {
+                    false
+                } else if token_id == &pair.token_out {
+                    true
+                } else {
+                    return Err("The pair does not trade this token");
+                };
+                self.internal_create_position(sender_id, pair_id, args.amount_per_swap, args.swap_interval, reverse, max_slippage_bps, amount.0);
+            }
+            TransferMessage::TopupPosition { position_id } => {
+                self.internal_topup_position(token_id, &sender_id, position_id, amount)?;
+            }
+            TransferMessage::Topup {} => {
+                let selling_positions: Vec<PositionId> = self.account_position_ids(&sender_id)
+                    .into_iter()
+                    .filter(|position_id| &self.position_tokens(self.positions.get(position_id).unwrap()).0 == token_id)
+                    .collect();
+                let [position_id] = selling_positions[..] else {
+                    return Err("Sender must have exactly one position selling the token, use topup_position");
+                };
+                self.internal_topup_position(token_id, &sender_id, position_id, amount)?;
+            }
+            TransferMessage::BatchOutput { batch_id } => {
+                self.internal_receive_batch_output(token_id, &sender_id, batch_id, amount)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn internal_topup_position(&mut self, token_id: &AccountId, sender_id: &AccountId, position_id: PositionId, amount: U128) -> Result<(), &'static str> {
+        // position must exist, belong to the sender and sell the token
+        let mut position = self.positions.get(&position_id).ok_or("Position does not exist")?.clone();
+        if &position.wallet != sender_id {
+            return Err("Position belongs to another account");
+        }
+        if &self.position_tokens(&position).0 != token_id {
+            return Err("The position does not sell this token");
+        }
+        position.amount = U128(position.amount.0.checked_add(amount.0).ok_or("Overflow")?); // add amount;
+        self.internal_save_position(position);
+        DcaEvent::PositionToppedUp { position_id, account_id: sender_id, token_id, amount }.emit();
+        Ok(())
+    }
+}
+
+//implementation of the NEP-141 receiver
+#[near]
+impl FungibleTokenReceiver for Contract {
+    /// This is how positions selling a token other than wNEAR are opened and funded.
+    /// Deposits that cannot be applied are refunded in full.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        // get the contract ID which is the predecessor
+        let ft_contract_id = env::predecessor_account_id();
+        // Ensure only tokens of the registered pairs can be used
+        let is_traded = self.pairs.values().any(|pair| pair.token_in == ft_contract_id || pair.token_out == ft_contract_id);
+        assert!(is_traded, "The FT token {} is not traded by any pair", ft_contract_id);
+
+        // the deposit belongs to sender_id as reported by the token contract, which
+        // can be a contract such as a DAO rather than the signer of the transaction
+
+        let message = if msg.is_empty() {
+            Ok(TransferMessage::Topup {})
+        } else {
+            serde_json::from_str::<TransferMessage>(&msg).map_err(|_| "Invalid message")
+        };
+
+        match message.and_then(|message| self.internal_on_transfer(&ft_contract_id, sender_id, amount, message)) {
+            // We don't return any FTs to the sender because we're storing all of them in their balance
+            Ok(()) => PromiseOrValue::Value(U128(0)),
+            Err(error) => {
+                log!("Refunding {}: {}", amount.0, error);
+                PromiseOrValue::Value(amount)
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use near_sdk::{env, near};
+
+use crate::pair::PairId;
+use crate::position::{Position, PositionId};
+use crate::Contract;
+
+// Key of the due index: the pair and direction of the position, so that positions
+// batched together are next to each other, then the timestamp at which the next swap
+// is due, and the position so that positions due at the same time get distinct entries.
+pub type DueKey = (PairId, bool, u64, PositionId);
+
+// Most due positions counted for each direction by get_pending_swaps, so the view
+// reads a bounded part of the due index
+pub const MAX_COUNTED_DUE_POSITIONS: usize = 1_000;
+
+// Number of positions whose swap is due and that are waiting to be batched, at most
+// MAX_COUNTED_DUE_POSITIONS
+#[near(serializers = [json])]
+pub struct PendingSwaps {
+    pub forward: u32,
+    pub reverse: u32,
+}
+
+impl Position {
+    /// Timestamp from which the next swap of the position can be executed.
+    pub fn next_swap_timestamp(&self) -> u64 {
+        self.last_swap_timestamp.saturating_add(self.swap_interval)
+    }
+
+    /// A position is kept in the due index only while it is active, its balance
+    /// covers at least one swap and it is not part of a batch in flight.
+    pub fn is_schedulable(&self) -> bool {
+        !self.pause && self.amount >= self.amount_per_swap && self.in_flight.is_none()
+    }
+
+    fn due_key(&self) -> DueKey {
+        (self.pair_id, self.reverse, self.next_swap_timestamp(), self.id)
+    }
+}
+
+impl Contract {
+    /// Entries of the positions of the pair and direction that are due at `now`,
+    /// the longest waiting first.
+    fn due_entries(&self, pair_id: PairId, reverse: bool, now: u64) -> impl Iterator<Item = &DueKey> {
+        self.due.range((pair_id, reverse, 0, 0)..=(pair_id, reverse, now, PositionId::MAX))
+            .map(|(key, _)| key)
+    }
+
+    /// Whether a position of the pair and direction is due.
+    pub(crate) fn has_due_positions(&self, pair_id: PairId, reverse: bool) -> bool {
+        self.due_entries(pair_id, reverse, env::block_timestamp()).next().is_some()
+    }
+
+    /// Stores the position and moves its entry in the due index to match the new state.
+    /// Every change to a stored position must go through here.
+    pub(crate) fn internal_save_position(&mut self, position: Position) {
+        let previous = self.positions.get(&position.id).cloned();
+        if let Some(previous) = &previous {
+            self.unschedule(previous);
+        }
+        self.internal_track_position(previous.as_ref(), Some(&position));
+        self.schedule(&position);
+        self.positions.insert(position.id, position);
+    }
+
+    /// Removes the position together with its entry in the due index and in the
+    /// positions of its account.
+    pub(crate) fn internal_remove_position(&mut self, position_id: PositionId) -> Option<Position> {
+        let position = self.positions.remove(&position_id)?;
+        self.unschedule(&position);
+        self.internal_track_position(Some(&position), None);
+
+        let mut position_ids = self.account_position_ids(&position.wallet);
+        position_ids.retain(|id| *id != position_id);
+        if position_ids.is_empty() {
+            self.account_positions.remove(&position.wallet);
+            self.metrics.accounts -= 1;
+        } else {
+            self.account_positions.insert(position.wallet.clone(), position_ids);
+        }
+
+        Some(position)
+    }
+
+    /// Positions of the pair and direction whose swap is due, the longest waiting first.
+    /// Served positions are rescheduled after their new `last_swap_timestamp`, so
+    /// positions left out of a full batch are at the front of the next one.
+    pub(crate) fn due_positions(&self, pair_id: PairId, reverse: bool, limit: usize) -> Vec<PositionId> {
+        self.due_entries(pair_id, reverse, env::block_timestamp())
+            .take(limit)
+            .map(|(_, _, _, position_id)| *position_id)
+            .collect()
+    }
+
+    /// Number of positions of the pair and direction whose swap is due, counted up to
+    /// MAX_COUNTED_DUE_POSITIONS.
+    pub(crate) fn count_due_positions(&self, pair_id: PairId, reverse: bool) -> u32 {
+        self.due_entries(pair_id, reverse, env::block_timestamp()).take(MAX_COUNTED_DUE_POSITIONS).count() as u32
+    }
+
+    fn schedule(&mut self, position: &Position) {
+        if position.is_schedulable() {
+            self.due.insert(position.due_key(), ());
+        }
+    }
+
+    fn unschedule(&mut self, position: &Position) {
+        self.due.remove(&position.due_key());
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+
+use near_sdk::json_types::U128;
+use near_sdk::{near, AccountId};
+
+use crate::batch::BatchId;
+use crate::math::average_price;
+use crate::pair::PairId;
+use crate::position::PositionId;
+use crate::{Contract, ContractExt};
+
+// Most recent fills kept for each account, older ones are dropped
+pub const MAX_RECENT_FILLS: usize = 20;
+
+// Swap of one position in a settled batch
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fill {
+    pub batch_id: BatchId,
+    pub position_id: PositionId,
+    pub pair_id: PairId,
+    pub reverse: bool,
+    // amount_per_swap of the position, fee included
+    pub amount_in: U128,
+    pub amount_out: U128,
+    pub fee: U128,
+    pub timestamp: u64,
+}
+
+// Totals of the swaps of an account in one direction of a pair. Unlike the balances of
+// the positions, they are not reduced by withdrawals or closing positions.
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TradeStats {
+    pub pair_id: PairId,
+    pub reverse: bool,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    // input spent, fee included, and output received
+    pub amount_in: U128,
+    pub amount_out: U128,
+    pub fees: U128,
+    pub swaps: u64,
+    // input paid per unit of output, scaled by PRICE_PRECISION
+    pub average_price: Option<U128>,
+    pub first_swap_timestamp: u64,
+    pub last_swap_timestamp: u64,
+}
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserStats {
+    pub trades: Vec<TradeStats>,
+    // latest fills last, at most MAX_RECENT_FILLS
+    pub recent_fills: VecDeque<Fill>,
+}
+
+impl Contract {
+    /// Adds the swap of a position to the statistics of its account.
+    pub(crate) fn internal_record_fill(&mut self, account_id: &AccountId, tokens: (&AccountId, &AccountId), fill: Fill) {
+        let stats = self.user_stats.entry(account_id.clone()).or_default();
+        let timestamp = fill.timestamp;
+
+        let index = match stats.trades.iter().position(|trade| trade.pair_id == fill.pair_id && trade.reverse == fill.reverse) {
+            Some(index) => index,
+            None => {
+                stats.trades.push(TradeStats {
+                    pair_id: fill.pair_id,
+                    reverse: fill.reverse,
+                    token_in: tokens.0.clone(),
+                    token_out: tokens.1.clone(),
+                    amount_in: U128(0),
+                    amount_out: U128(0),
+                    fees: U128(0),
+                    swaps: 0,
+                    average_price: None,
+                    first_swap_timestamp: timestamp,
+                    last_swap_timestamp: timestamp,
+                });
+                stats.trades.len() - 1
+            }
+        };
+        let trade = &mut stats.trades[index];
+        trade.amount_in = U128(trade.amount_in.0.checked_add(fill.amount_in.0).expect("Overflow"));
+        trade.amount_out = U128(trade.amount_out.0.checked_add(fill.amount_out.0).expect("Overflow"));
+        trade.fees = U128(trade.fees.0.checked_add(fill.fee.0).expect("Overflow"));
+        trade.swaps += 1;
+        trade.average_price = average_price(trade.amount_in.0, trade.amount_out.0).map(U128);
+        trade.last_swap_timestamp = timestamp;
+
+        if stats.recent_fills.len() == MAX_RECENT_FILLS {
+            stats.recent_fills.pop_front();
+        }
+        stats.recent_fills.push_back(fill);
+    }
+}
+
+#[near]
+impl Contract {
+    // Totals of the swaps of the account per pair and direction, with its latest fills
+    pub fn get_user_stats(&self, account_id: AccountId) -> UserStats {
+        self.user_stats.get(&account_id).cloned().unwrap_or_default()
+    }
+}